@@ -5,12 +5,74 @@ use dirs::home_dir;
 use serde_derive::{Deserialize, Serialize};
 use toml;
 
+use crate::application::output::OutputKind;
+use crate::application::player::GainMode;
+use crate::application::queue::QueueEnd;
+
+// The longest crossfade in seconds; any longer and most
+// of a short track would be spent fading in or out
+pub const MAX_CROSSFADE: u64 = 12;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub music_folder: String,
     pub data_folder: String,
     pub database_path: String,
     pub stats_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plays_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smart_playlists_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlists_folder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_path: Option<String>,
+    // Pick up paused where the last session left off
+    #[serde(default = "default_resume_playback")]
+    pub resume_playback: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratings_path: Option<String>,
+    // How many tracks apart the artist spread shuffle keeps an artist
+    #[serde(default = "default_shuffle_spread")]
+    pub shuffle_spread: usize,
+    // How many tracks radio mode waits before playing one again
+    #[serde(default = "default_radio_window")]
+    pub radio_window: usize,
+    // One of stop, repeat, random, next_album or quit
+    #[serde(default)]
+    pub queue_end: QueueEnd,
+    // Seconds skipped by the seek keys
+    #[serde(default = "default_seek_step")]
+    pub seek_step: u64,
+    // Between 0 and 1, kept from the last time sonik was closed
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+    // Seconds the end of a track overlaps the next one, up to MAX_CROSSFADE
+    #[serde(default)]
+    pub crossfade: u64,
+    // Never crossfade between tracks from the same album
+    #[serde(default = "default_prefer_gapless")]
+    pub prefer_gapless: bool,
+    // One of off, track or album
+    #[serde(default)]
+    pub replay_gain: GainMode,
+    // Decibels added on top of the ReplayGain values
+    #[serde(default)]
+    pub replay_gain_preamp: f32,
+    // Decibels to turn files without ReplayGain tags up or down by
+    #[serde(default = "default_untagged_gain")]
+    pub untagged_gain: f32,
+    // Measure the loudness of albums without ReplayGain tags when scanning
+    #[serde(default)]
+    pub analyze_on_scan: bool,
+    // One of auto, null or wav
+    #[serde(default)]
+    pub output: OutputKind,
+    // Where the wav output records to, output.wav in the data folder if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_file: Option<String>,
 }
 
 impl Config {
@@ -35,6 +97,26 @@ impl Config {
             data_folder: data_folder.to_str().unwrap().to_owned(),
             database_path: database_path.to_str().unwrap().to_owned(),
             stats_path: stats_path.to_str().unwrap().to_owned(),
+            index_path: None,
+            plays_path: None,
+            smart_playlists_path: None,
+            playlists_folder: None,
+            session_path: None,
+            resume_playback: default_resume_playback(),
+            ratings_path: None,
+            shuffle_spread: default_shuffle_spread(),
+            radio_window: default_radio_window(),
+            queue_end: QueueEnd::default(),
+            seek_step: default_seek_step(),
+            volume: default_volume(),
+            crossfade: 0,
+            prefer_gapless: default_prefer_gapless(),
+            replay_gain: GainMode::default(),
+            replay_gain_preamp: 0.,
+            untagged_gain: default_untagged_gain(),
+            analyze_on_scan: false,
+            output: OutputKind::default(),
+            output_file: None,
         }
     }
 
@@ -55,6 +137,26 @@ impl Config {
             data_folder: data_folder.to_str().unwrap().to_owned(),
             database_path: database_path.to_str().unwrap().to_owned(),
             stats_path: stats_path.to_str().unwrap().to_owned(),
+            index_path: None,
+            plays_path: None,
+            smart_playlists_path: None,
+            playlists_folder: None,
+            session_path: None,
+            resume_playback: default_resume_playback(),
+            ratings_path: None,
+            shuffle_spread: default_shuffle_spread(),
+            radio_window: default_radio_window(),
+            queue_end: QueueEnd::default(),
+            seek_step: default_seek_step(),
+            volume: default_volume(),
+            crossfade: 0,
+            prefer_gapless: default_prefer_gapless(),
+            replay_gain: GainMode::default(),
+            replay_gain_preamp: 0.,
+            untagged_gain: default_untagged_gain(),
+            analyze_on_scan: false,
+            output: OutputKind::default(),
+            output_file: None,
         };
 
         let mut config_path: PathBuf = home_dir().unwrap();
@@ -84,10 +186,85 @@ impl Config {
 
         // Create the configuration
         let config_string = fs::read_to_string(&config_path).unwrap();
-        let config: Config = toml::from_str(&config_string).unwrap();
+        let mut config: Config = toml::from_str(&config_string).unwrap();
+
+        // Keep hand edited values within what sonik can handle
+        config.volume = if config.volume.is_nan() {
+            default_volume()
+        } else {
+            config.volume.clamp(0., 1.)
+        };
+        config.crossfade = config.crossfade.min(MAX_CROSSFADE);
+
+        // Older versions wrote out every path in full, which would keep
+        // the files from following data_folder, so those are forgotten
+        for (path, name) in &mut [
+            (&mut config.index_path, "library.idx"),
+            (&mut config.plays_path, "plays.db"),
+            (&mut config.smart_playlists_path, "smart_playlists.toml"),
+            (&mut config.playlists_folder, "playlists"),
+            (&mut config.session_path, "session.db"),
+            (&mut config.ratings_path, "ratings.db"),
+            (&mut config.output_file, "output.wav"),
+        ] {
+            if path.as_deref() == Some(old_data_file(name).as_str()) {
+                **path = None;
+            }
+        }
+        fs::create_dir_all(&config.data_folder).ok();
 
         Ok(config)
     }
+
+    // Writes the configuration back out, e.g. to keep the volume
+    pub fn save(&self) -> Result<(), ()> {
+        let mut config_path: PathBuf = home_dir().unwrap();
+        config_path.push(".sonik");
+        config_path.push("config.toml");
+
+        let config_as_str = toml::to_string(self).map_err(|_| ())?;
+        fs::write(&config_path, config_as_str).map_err(|_| ())
+    }
+
+    // Files added after the first release live in the data folder,
+    // unless the configuration gives a path of its own
+    fn data_file(&self, path: &Option<String>, name: &str) -> String {
+        match path {
+            Some(p) => p.clone(),
+            None => Path::new(&self.data_folder)
+                .join(name)
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    pub fn index_path(&self) -> String {
+        self.data_file(&self.index_path, "library.idx")
+    }
+
+    pub fn plays_path(&self) -> String {
+        self.data_file(&self.plays_path, "plays.db")
+    }
+
+    pub fn smart_playlists_path(&self) -> String {
+        self.data_file(&self.smart_playlists_path, "smart_playlists.toml")
+    }
+
+    pub fn playlists_folder(&self) -> String {
+        self.data_file(&self.playlists_folder, "playlists")
+    }
+
+    pub fn session_path(&self) -> String {
+        self.data_file(&self.session_path, "session.db")
+    }
+
+    pub fn ratings_path(&self) -> String {
+        self.data_file(&self.ratings_path, "ratings.db")
+    }
+
+    pub fn output_file(&self) -> String {
+        self.data_file(&self.output_file, "output.wav")
+    }
 }
 
 fn write_default_config(path: &Path) -> Option<()> {
@@ -99,3 +276,42 @@ fn write_default_config(path: &Path) -> Option<()> {
     let config_as_str = toml::to_string(&Config::default()).unwrap();
     fs::write(path.to_string_lossy().into_owned(), config_as_str).ok()
 }
+
+// Where older versions put a file, whatever the data folder was set to
+fn old_data_file(name: &str) -> String {
+    let mut path = home_dir().unwrap();
+    path.push(".sonik");
+    path.push(name);
+
+    path.to_str().unwrap().to_owned()
+}
+
+fn default_resume_playback() -> bool {
+    true
+}
+
+fn default_shuffle_spread() -> usize {
+    3
+}
+
+fn default_radio_window() -> usize {
+    50
+}
+
+fn default_seek_step() -> u64 {
+    10
+}
+
+fn default_volume() -> f32 {
+    1.
+}
+
+fn default_prefer_gapless() -> bool {
+    true
+}
+
+// Untagged files tend to be mastered loud, so they are
+// turned down about as much as a typical tagged track
+fn default_untagged_gain() -> f32 {
+    -6.
+}
@@ -0,0 +1,434 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use id3::{Tag, Version};
+use rodio::{Decoder, Source};
+
+use crate::storage::record::{Artist, ReplayGain, Track};
+
+// ReplayGain 2.0 turns everything up or down to this many LUFS
+const REFERENCE_LOUDNESS: f64 = -18.;
+// Blocks quieter than this are left out altogether
+const ABSOLUTE_GATE: f64 = -70.;
+// Blocks this far below the ungated loudness are left out too
+const RELATIVE_GATE: f64 = 10.;
+// Peaks are found by upsampling this many times, to catch the
+// ones that fall in between samples
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+// The loudness of a track or album as measured by EBU R128
+pub struct Measurement {
+    // Integrated loudness in LUFS, None if it was all silence
+    pub loudness: Option<f64>,
+    // True peak, where 1 is full scale
+    pub peak: f64,
+    // Mean square of every 400ms block, kept to measure albums with
+    blocks: Vec<f64>,
+}
+
+impl Measurement {
+    // Silence, or a track too short to measure, is left as it is; it still
+    // gets a value so that it isn't measured over again every time
+    pub fn replay_gain(&self) -> ReplayGain {
+        let gain = match self.loudness {
+            Some(loudness) => (REFERENCE_LOUDNESS - loudness) as f32,
+            None => 0.,
+        };
+
+        ReplayGain {
+            gain,
+            peak: Some(self.peak as f32),
+        }
+    }
+}
+
+// A biquad filter, run once per channel
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+// The K-weighting curve: a shelf that boosts the highs the way hearing does,
+// then a high pass that cuts the lows. The coefficients are worked out for
+// the sample rate, as the ones in the standard are only for 48kHz.
+fn k_weighting(rate: f64) -> (Biquad, Biquad) {
+    let f0 = 1_681.974_450_955_533;
+    let gain = 3.999_843_853_973_347;
+    let q = 0.707_175_236_955_419_6;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1., 2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    let f0 = 38.135_470_876_024_44;
+    let q = 0.500_327_037_323_877_3;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1., -2., 1.],
+        a: [1., 2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    (shelf, high_pass)
+}
+
+// A windowed sinc low pass for upsampling, split into one
+// set of taps for each point between two samples
+fn oversampling_filter() -> Vec<[f64; TAPS_PER_PHASE]> {
+    let len = OVERSAMPLING * TAPS_PER_PHASE;
+    let centre = (len - 1) as f64 / 2.;
+
+    let mut phases = vec![[0.; TAPS_PER_PHASE]; OVERSAMPLING];
+    for (phase, taps) in phases.iter_mut().enumerate() {
+        for (j, tap) in taps.iter_mut().enumerate() {
+            let n = (phase + j * OVERSAMPLING) as f64;
+            let x = (n - centre) / OVERSAMPLING as f64;
+            let sinc = if x == 0. {
+                1.
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.5 - 0.5 * (2. * PI * (n + 0.5) / len as f64).cos();
+            *tap = sinc * window;
+        }
+
+        // Each phase should pass a steady signal through as it is
+        let sum: f64 = taps.iter().sum();
+        for tap in taps.iter_mut() {
+            *tap /= sum;
+        }
+    }
+
+    phases
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10. * power.log10()
+}
+
+// Gates the blocks as EBU R128 does and gives the loudness of the rest
+fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let audible: Vec<f64> = blocks
+        .iter()
+        .cloned()
+        .filter(|&p| p > 0. && block_loudness(p) > ABSOLUTE_GATE)
+        .collect();
+    if audible.is_empty() {
+        return None;
+    }
+
+    let threshold = block_loudness(mean(&audible)) - RELATIVE_GATE;
+    let gated: Vec<f64> = audible
+        .into_iter()
+        .filter(|&p| block_loudness(p) > threshold)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(block_loudness(mean(&gated)))
+}
+
+// Only reads as far as the format needs to, so it's quick to
+// find out whether a file can be measured at all
+fn open(path: &str) -> Result<Decoder<BufReader<File>>, ()> {
+    let file = File::open(path).map_err(|_| ())?;
+    let decoder = Decoder::new(BufReader::new(file)).map_err(|_| ())?;
+    if decoder.channels() == 0 || decoder.sample_rate() == 0 {
+        return Err(());
+    }
+
+    Ok(decoder)
+}
+
+// Decodes the whole file and measures it
+pub fn measure(path: &str) -> Result<Measurement, ()> {
+    let decoder = open(path)?;
+    let channels = decoder.channels() as usize;
+    let rate = u64::from(decoder.sample_rate());
+
+    let (shelf, high_pass) = k_weighting(rate as f64);
+    let mut filters = vec![(shelf, high_pass); channels];
+    let phases = oversampling_filter();
+    let mut history = vec![[0.; TAPS_PER_PHASE]; channels];
+
+    // Blocks are 400ms long and start every 100ms, so each one
+    // is made up of the last four 100ms steps
+    let step_len = (rate / 10) as usize;
+    let mut steps: Vec<f64> = Vec::new();
+    let mut step_sum = 0.;
+    let mut step_frames = 0;
+    let mut blocks = Vec::new();
+    let mut peak: f64 = 0.;
+
+    for (i, sample) in decoder.enumerate() {
+        let channel = i % channels;
+        let x = f64::from(sample) / 32768.;
+
+        let (shelf, high_pass) = &mut filters[channel];
+        let weighted = high_pass.process(shelf.process(x));
+        step_sum += weighted * weighted;
+
+        let recent = &mut history[channel];
+        recent.rotate_right(1);
+        recent[0] = x;
+        peak = peak.max(x.abs());
+        for taps in &phases {
+            let y: f64 = taps.iter().zip(recent.iter()).map(|(h, x)| h * x).sum();
+            peak = peak.max(y.abs());
+        }
+
+        if channel == channels - 1 {
+            step_frames += 1;
+            if step_frames == step_len {
+                steps.push(step_sum);
+                step_sum = 0.;
+                step_frames = 0;
+
+                if steps.len() == 4 {
+                    let total: f64 = steps.iter().sum();
+                    blocks.push(total / (4 * step_len) as f64);
+                    steps.remove(0);
+                }
+            }
+        }
+    }
+
+    Ok(Measurement {
+        loudness: integrated_loudness(&blocks),
+        peak,
+        blocks,
+    })
+}
+
+// An album is measured as if it were one long track
+pub fn measure_album(tracks: &[Measurement]) -> Measurement {
+    let blocks: Vec<f64> = tracks
+        .iter()
+        .flat_map(|m| m.blocks.iter().cloned())
+        .collect();
+
+    Measurement {
+        loudness: integrated_loudness(&blocks),
+        peak: tracks.iter().map(|m| m.peak).fold(0., f64::max),
+        blocks,
+    }
+}
+
+// Only MP3s are tagged, as ID3 tags don't belong in other formats
+pub fn write_tags(track: &Track) -> Result<(), ()> {
+    let is_mp3 = matches!(
+        Path::new(&track.file_path).extension().and_then(|e| e.to_str()),
+        Some(ext) if ext.eq_ignore_ascii_case("mp3")
+    );
+    if !is_mp3 {
+        return Ok(());
+    }
+
+    let mut tag = Tag::read_from_path(&track.file_path).map_err(|_| ())?;
+    for (scope, gain) in &[("TRACK", track.track_gain), ("ALBUM", track.album_gain)] {
+        let gain = match gain {
+            Some(g) => g,
+            None => continue,
+        };
+
+        let name = format!("REPLAYGAIN_{}_GAIN", scope);
+        tag.remove_extended_text(Some(&name), None);
+        tag.add_extended_text(name, format!("{:.2} dB", gain.gain));
+
+        if let Some(peak) = gain.peak {
+            let name = format!("REPLAYGAIN_{}_PEAK", scope);
+            tag.remove_extended_text(Some(&name), None);
+            tag.add_extended_text(name, format!("{:.6}", peak));
+        }
+    }
+
+    // Written back as the version it was read as, except for ID3v2.2
+    // which can't be written, so it becomes the closest one that can
+    let version = match tag.version() {
+        Version::Id3v22 => Version::Id3v23,
+        version => version,
+    };
+    tag.write_to_path(&track.file_path, version).map_err(|_| ())
+}
+
+// Measures every album that has a track without ReplayGain values, or
+// every album if told to, and fills the values in. Returns how many
+// tracks were measured and how many could not be.
+pub fn analyze_library(library: &mut [Artist], everything: bool, tag: bool) -> (usize, usize) {
+    let mut measured = 0;
+    let mut failed = 0;
+
+    for album in library.iter_mut().flat_map(|a| a.albums.iter_mut()) {
+        if !everything {
+            let missing = album
+                .tracks
+                .iter()
+                .filter(|t| t.track_gain.is_none() || t.album_gain.is_none())
+                .collect::<Vec<&Track>>();
+            if missing.is_empty() {
+                continue;
+            }
+
+            // Tracks that couldn't be measured last time would only fail
+            // again, so once they are all that's left the album is left alone
+            if missing.iter().all(|t| open(&t.file_path).is_err()) {
+                for track in missing {
+                    println!("Error: Could not analyze {}", track.file_path);
+                    failed += 1;
+                }
+                continue;
+            }
+        }
+
+        println!("Analyzing {} - {}", album.artist, album.title);
+
+        let mut measurements = Vec::new();
+        for track in album.tracks.iter_mut() {
+            match measure(&track.file_path) {
+                Ok(m) => {
+                    track.track_gain = Some(m.replay_gain());
+                    measurements.push(m);
+                    measured += 1;
+                }
+                Err(()) => {
+                    println!("Error: Could not analyze {}", track.file_path);
+                    failed += 1;
+                }
+            }
+        }
+
+        if measurements.is_empty() {
+            continue;
+        }
+
+        album.gain = Some(measure_album(&measurements).replay_gain());
+        for track in album.tracks.iter_mut() {
+            track.album_gain = album.gain;
+            if tag && write_tags(track).is_err() {
+                println!("Error: Could not write tags to {}", track.file_path);
+            }
+        }
+    }
+
+    (measured, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::record::Album;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A 1kHz stereo sine wave, with the peak at the given level
+    fn write_sine(path: &Path, dbfs: f64, millis: u32) {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let amplitude = 10f64.powf(dbfs / 20.) * 32768.;
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for i in 0..48 * millis {
+            let t = f64::from(i) / 48000.;
+            let sample = (amplitude * (2. * PI * 1000. * t).sin()).round() as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn scratch_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sonik-{}-{}", std::process::id(), name))
+    }
+
+    fn track(path: &Path) -> Track {
+        Track {
+            file_path: path.to_string_lossy().into_owned(),
+            ..Track::dummy()
+        }
+    }
+
+    // The reference tone from EBU Tech 3341, which should read -23 LUFS
+    #[test]
+    fn measures_the_reference_tone() {
+        let path = scratch_file("tone.wav");
+        write_sine(&path, -23., 3000);
+        let measurement = measure(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let measurement = measurement.unwrap();
+        let loudness = measurement.loudness.unwrap();
+        assert!((loudness + 23.).abs() < 0.1, "measured {} LUFS", loudness);
+        assert!((measurement.peak - 10f64.powf(-23. / 20.)).abs() < 0.001);
+
+        let gain = measurement.replay_gain();
+        assert!((gain.gain - 5.).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(integrated_loudness(&[]), None);
+        assert_eq!(integrated_loudness(&[0.; 20]), None);
+        // Quieter than the absolute gate
+        assert_eq!(integrated_loudness(&[1e-9; 20]), None);
+        assert!(integrated_loudness(&[0.01; 20]).is_some());
+    }
+
+    #[test]
+    fn tracks_that_cant_be_read_are_only_tried_once() {
+        let good = scratch_file("good.wav");
+        write_sine(&good, -23., 1000);
+        let gone = scratch_file("gone.wav");
+        let mut library = vec![Artist {
+            title: "artist".to_string(),
+            albums: vec![Album {
+                title: "album".to_string(),
+                artist: "artist".to_string(),
+                year: 0,
+                tracks: vec![track(&good), track(&gone)],
+                gain: None,
+            }],
+        }];
+
+        assert_eq!(analyze_library(&mut library, false, false), (1, 1));
+        let album = &library[0].albums[0];
+        assert!(album.gain.is_some());
+        assert!(album.tracks[0].track_gain.is_some());
+        assert_eq!(album.tracks[1].track_gain, None);
+
+        // Only the missing track is left, and it isn't worth decoding the album for
+        fs::remove_file(&good).unwrap();
+        assert_eq!(analyze_library(&mut library, false, false), (0, 1));
+        assert!(library[0].albums[0].tracks[0].track_gain.is_some());
+    }
+}
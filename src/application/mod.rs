@@ -1,3 +1,8 @@
 pub mod config;
+pub mod loudness;
+pub mod output;
+pub mod player;
 pub mod queue;
+pub mod radio;
+pub mod source;
 pub mod state;
@@ -0,0 +1,289 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::source::UniformSourceIterator;
+use rodio::{Device, Sink, Source};
+use serde_derive::{Deserialize, Serialize};
+
+pub type BoxedSource = Box<dyn Source<Item = i16> + Send>;
+
+// How much audio the paced outputs take at a time
+const CHUNK: Duration = Duration::from_millis(20);
+// How often the WAV header is brought up to date, so the
+// file is readable even if sonik doesn't exit cleanly
+const WAV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const WAV_SAMPLE_RATE: u32 = 44100;
+const WAV_CHANNELS: u16 = 2;
+
+// Where the audio thread sends what it plays
+pub trait OutputBackend: Send {
+    // Starts playing the source, dropping whatever was playing before
+    fn play(&mut self, source: BoxedSource);
+    fn stop(&mut self);
+    fn pause(&mut self);
+    fn resume(&mut self);
+    fn is_paused(&self) -> bool;
+    // Whether everything given to it has been played
+    fn is_empty(&self) -> bool;
+    fn set_volume(&mut self, volume: f32);
+}
+
+// Which output to use, set with `output` in the configuration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputKind {
+    // The sound card, or nothing if there isn't one
+    #[default]
+    Auto,
+    Null,
+    // Records everything played to a WAV file
+    Wav,
+}
+
+pub struct RodioOutput {
+    device: Device,
+    sink: Sink,
+    volume: f32,
+}
+
+impl RodioOutput {
+    pub fn new(device: Device) -> RodioOutput {
+        RodioOutput {
+            sink: Sink::new(&device),
+            device,
+            volume: 1.,
+        }
+    }
+}
+
+impl OutputBackend for RodioOutput {
+    fn play(&mut self, source: BoxedSource) {
+        self.stop();
+        self.sink.append(source);
+    }
+
+    // The clear function does not work for rodio::Sink, so the
+    // sink field is just reassigned and it works just as well
+    fn stop(&mut self) {
+        self.sink = Sink::new(&self.device);
+        self.sink.set_volume(self.volume);
+    }
+
+    fn pause(&mut self) {
+        self.sink.pause();
+    }
+
+    fn resume(&mut self) {
+        self.sink.play();
+    }
+
+    fn is_paused(&self) -> bool {
+        self.sink.is_paused()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sink.empty()
+    }
+
+    // Kept around as the sink is replaced for every source
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        self.sink.set_volume(volume);
+    }
+}
+
+// What a paced output and the thread playing its source both look at
+struct Playback {
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    done: AtomicBool,
+}
+
+// Takes samples at the rate a sound card would, so that playback takes
+// as long as it normally does, and hands them to the writer if there is one
+pub struct PacedOutput {
+    writer: Option<Arc<Mutex<WavWriter<BufWriter<File>>>>>,
+    volume: Arc<Mutex<f32>>,
+    paused: bool,
+    playback: Option<Arc<Playback>>,
+}
+
+impl PacedOutput {
+    // Plays into nothing at all
+    pub fn null() -> PacedOutput {
+        PacedOutput {
+            writer: None,
+            volume: Arc::new(Mutex::new(1.)),
+            paused: false,
+            playback: None,
+        }
+    }
+
+    // Records to a 16 bit stereo WAV file, converting
+    // whatever is played to the one sample rate
+    pub fn wav(path: &str) -> Result<PacedOutput, ()> {
+        let spec = WavSpec {
+            channels: WAV_CHANNELS,
+            sample_rate: WAV_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let writer = WavWriter::create(path, spec).map_err(|_| ())?;
+
+        Ok(PacedOutput {
+            writer: Some(Arc::new(Mutex::new(writer))),
+            ..PacedOutput::null()
+        })
+    }
+}
+
+impl OutputBackend for PacedOutput {
+    // Starts out playing, as a new rodio sink does
+    fn play(&mut self, source: BoxedSource) {
+        self.stop();
+        self.paused = false;
+
+        let playback = Arc::new(Playback {
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        });
+        self.playback = Some(playback.clone());
+
+        let writer = self.writer.clone();
+        let volume = self.volume.clone();
+        let source: BoxedSource = match writer {
+            Some(_) => Box::new(UniformSourceIterator::new(
+                source,
+                WAV_CHANNELS,
+                WAV_SAMPLE_RATE,
+            )),
+            None => source,
+        };
+
+        thread::spawn(move || consume(source, &playback, writer, &volume));
+    }
+
+    // Stopped while holding the writer, so that once this returns the
+    // old thread can't write anything more after the next source starts
+    fn stop(&mut self) {
+        if let Some(playback) = self.playback.take() {
+            let _writer = self.writer.as_ref().map(|w| w.lock().unwrap());
+            playback.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn pause(&mut self) {
+        self.paused = true;
+        if let Some(playback) = &self.playback {
+            playback.paused.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn resume(&mut self) {
+        self.paused = false;
+        if let Some(playback) = &self.playback {
+            playback.paused.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn is_empty(&self) -> bool {
+        match &self.playback {
+            Some(playback) => playback.done.load(Ordering::Relaxed),
+            None => true,
+        }
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        *self.volume.lock().unwrap() = volume;
+    }
+}
+
+// Pulls a chunk of samples at a time, waiting until it would have been
+// played before taking the next one, until the source runs out or is stopped
+fn consume(
+    mut source: BoxedSource,
+    playback: &Playback,
+    writer: Option<Arc<Mutex<WavWriter<BufWriter<File>>>>>,
+    volume: &Mutex<f32>,
+) {
+    let mut due = Instant::now();
+    let mut last_flush = Instant::now();
+
+    while !playback.stopped.load(Ordering::Relaxed) {
+        if playback.paused.load(Ordering::Relaxed) {
+            thread::sleep(CHUNK);
+            due = Instant::now();
+            continue;
+        }
+
+        let per_sec = source.sample_rate() as usize * source.channels() as usize;
+        let count = per_sec * CHUNK.as_millis() as usize / 1000;
+        let samples = source.by_ref().take(count.max(1)).collect::<Vec<i16>>();
+        if samples.is_empty() {
+            break;
+        }
+
+        if let Some(writer) = &writer {
+            let volume = *volume.lock().unwrap();
+            let mut writer = writer.lock().unwrap();
+            if playback.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            for sample in &samples {
+                let scaled = f32::from(*sample) * volume;
+                let scaled = scaled.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+                if writer.write_sample(scaled).is_err() {
+                    break;
+                }
+            }
+
+            if last_flush.elapsed() >= WAV_FLUSH_INTERVAL {
+                if let Ok(()) = writer.flush() {}
+                last_flush = Instant::now();
+            }
+        }
+
+        due += Duration::from_millis(samples.len() as u64 * 1000 / per_sec.max(1) as u64);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+
+    if let Some(writer) = &writer {
+        if let Ok(()) = writer.lock().unwrap().flush() {}
+    }
+    playback.done.store(true, Ordering::Relaxed);
+}
+
+// Picks the output set in the configuration, falling back to no
+// output at all when there is no sound card or the file can't be made
+pub fn open_output(kind: OutputKind, wav_path: &str) -> Box<dyn OutputBackend> {
+    match kind {
+        OutputKind::Auto => match rodio::default_output_device() {
+            Some(device) => Box::new(RodioOutput::new(device)),
+            None => {
+                println!("No audio output device found, playing to nothing");
+                Box::new(PacedOutput::null())
+            }
+        },
+        OutputKind::Null => Box::new(PacedOutput::null()),
+        OutputKind::Wav => match PacedOutput::wav(wav_path) {
+            Ok(output) => Box::new(output),
+            Err(()) => {
+                println!("Error: Could not write to {}, playing to nothing", wav_path);
+                Box::new(PacedOutput::null())
+            }
+        },
+    }
+}
@@ -0,0 +1,482 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::application::output::OutputBackend;
+use crate::application::source::{Chain, Shared, TrackSource};
+use crate::storage::record::Track;
+
+// How often the audio thread tells the UI where playback is
+const POSITION_INTERVAL: Duration = Duration::from_millis(250);
+
+// Which ReplayGain values playback goes by
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GainMode {
+    Off,
+    #[default]
+    Track,
+    // Keeps the quiet and loud tracks of an album as they were meant to be
+    Album,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Normalization {
+    pub mode: GainMode,
+    // Added to every gain, in decibels
+    pub preamp: f32,
+    // The gain for files with no ReplayGain tags, in decibels
+    pub untagged: f32,
+}
+
+impl Normalization {
+    // What to multiply the samples of the track by
+    pub fn factor(&self, track: &Track) -> f32 {
+        let tagged = match self.mode {
+            GainMode::Off => return 1.,
+            GainMode::Track => track.track_gain.or(track.album_gain),
+            GainMode::Album => track.album_gain.or(track.track_gain),
+        };
+
+        let (gain, peak) = match tagged {
+            Some(g) => (g.gain, g.peak),
+            None => (self.untagged, None),
+        };
+        let factor = 10f32.powf((gain + self.preamp) / 20.);
+
+        // Don't turn the track up any further than its peak allows
+        match peak {
+            Some(p) if p > 0. && factor * p > 1. => 1. / p,
+            _ => factor,
+        }
+    }
+}
+
+// Everything the UI can ask of the audio thread
+pub enum PlayerCommand {
+    // Plays and stops are numbered, so that events sent
+    // before the latest one can be told apart and ignored
+    Play(Track, u64),
+    Pause,
+    Resume,
+    Stop(u64),
+    Seek(Duration),
+    SetVolume(f32),
+    // What to carry straight on with once the playing track ends
+    SetNext(Option<Track>),
+}
+
+// Everything the audio thread tells the UI
+pub enum PlayerEvent {
+    // Playback ran on into the next track by itself, since the numbered play
+    Started(Track, u64),
+    // How far into the playing track playback is, not counting pauses
+    Position(Duration),
+    Finished(Track, u64),
+    // The track to be played could not be opened, and why
+    Unplayable(Track, String),
+}
+
+// Lives on its own thread, playing whatever the UI sends over
+pub struct Audio {
+    output: Box<dyn OutputBackend>,
+    commands: Receiver<PlayerCommand>,
+    events: Sender<PlayerEvent>,
+    crossfade: Duration,
+    prefer_gapless: bool,
+    normalization: Normalization,
+    shared: Arc<Mutex<Shared>>,
+    // Samples played of the current track, and how many make up a second
+    played: Arc<AtomicU64>,
+    samples_per_sec: u64,
+    // The number of the last play or stop
+    generation: u64,
+}
+
+impl Audio {
+    pub fn new(
+        output: Box<dyn OutputBackend>,
+        commands: Receiver<PlayerCommand>,
+        events: Sender<PlayerEvent>,
+    ) -> Audio {
+        Audio {
+            output,
+            commands,
+            events,
+            crossfade: Duration::from_secs(0),
+            prefer_gapless: true,
+            normalization: Normalization {
+                mode: GainMode::Off,
+                preamp: 0.,
+                untagged: 0.,
+            },
+            shared: Arc::new(Mutex::new(Shared::default())),
+            played: Arc::new(AtomicU64::new(0)),
+            samples_per_sec: 0,
+            generation: 0,
+        }
+    }
+
+    // Waits for commands until the UI goes away, checking in on
+    // the playing track whenever things have been quiet for a while
+    pub fn run(mut self) {
+        loop {
+            match self.commands.recv_timeout(POSITION_INTERVAL) {
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            self.check_progress();
+        }
+    }
+
+    fn handle(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Play(track, generation) => {
+                self.generation = generation;
+                self.play_from(track, Duration::from_secs(0));
+            }
+            PlayerCommand::Pause => self.output.pause(),
+            PlayerCommand::Resume => self.output.resume(),
+            PlayerCommand::Stop(generation) => {
+                self.generation = generation;
+                self.stop();
+            }
+            PlayerCommand::Seek(position) => self.seek(position),
+            PlayerCommand::SetVolume(volume) => self.set_volume(volume),
+            PlayerCommand::SetNext(track) => self.set_next(track),
+        }
+    }
+
+    fn send(&self, event: PlayerEvent) {
+        if let Ok(()) = self.events.send(event) {}
+    }
+
+    // Starts a track part of the way through, returning whether it could be opened
+    pub fn play_from(&mut self, track: Track, position: Duration) -> bool {
+        match TrackSource::open(&track, position, self.normalization.factor(&track)) {
+            Ok(source) => {
+                self.start_chain(source);
+                true
+            }
+            Err(()) => {
+                self.stop();
+                self.send_unplayable(track);
+                false
+            }
+        }
+    }
+
+    // Plays the source in place of whatever was playing, keeping hold of
+    // whatever was lined up next. The old chain keeps its own shared
+    // state, so it can't touch the new one while it's being dropped.
+    fn start_chain(&mut self, source: TrackSource) {
+        let next = self.shared.lock().unwrap().next.take();
+
+        self.shared = Arc::new(Mutex::new(Shared {
+            track: Some(source.track.clone()),
+            next,
+            crossfade: self.crossfade,
+            prefer_gapless: self.prefer_gapless,
+            generation: self.generation,
+        }));
+        self.played = Arc::new(AtomicU64::new(0));
+        self.samples_per_sec = source.samples_per_sec();
+
+        let chain = Chain::new(
+            source,
+            self.shared.clone(),
+            self.played.clone(),
+            self.events.clone(),
+        );
+        self.output.play(Box::new(chain));
+    }
+
+    // Opens the next track now, so that it's ready the moment the current one ends
+    pub fn set_next(&mut self, track: Option<Track>) {
+        // If it can't be opened, the current track just finishes
+        // and the UI finds out when it tries to play it
+        let next = track.and_then(|t| {
+            let gain = self.normalization.factor(&t);
+            TrackSource::open(&t, Duration::from_secs(0), gain).ok()
+        });
+        self.shared.lock().unwrap().next = next;
+    }
+
+    fn send_unplayable(&self, track: Track) {
+        let reason = if Path::new(&track.file_path).exists() {
+            "could not be decoded"
+        } else {
+            "file not found"
+        };
+        self.send(PlayerEvent::Unplayable(track, reason.to_string()));
+    }
+
+    fn current(&self) -> Option<Track> {
+        self.shared.lock().unwrap().track.clone()
+    }
+
+    pub fn position(&self) -> Duration {
+        if self.samples_per_sec == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let played = self.played.load(Ordering::Relaxed);
+        Duration::from_millis(played * 1000 / self.samples_per_sec)
+    }
+
+    // Lets the UI know when the track has run out, or otherwise where it's at
+    fn check_progress(&mut self) {
+        let track = match self.current() {
+            Some(t) => t,
+            None => return,
+        };
+
+        if self.output.is_empty() {
+            // The chain stops short of a track it can't run straight into,
+            // so that one is started here with only a very small gap
+            let next = {
+                let mut shared = self.shared.lock().unwrap();
+                shared.track = None;
+                shared.next.take()
+            };
+
+            match next {
+                Some(source) => {
+                    let next_track = source.track.clone();
+                    self.start_chain(source);
+                    self.send(PlayerEvent::Started(next_track, self.generation));
+                }
+                None => self.send(PlayerEvent::Finished(track, self.generation)),
+            }
+        } else if !self.output.is_paused() {
+            self.send(PlayerEvent::Position(self.position()));
+        }
+    }
+
+    // Starts the current track again from the position, staying
+    // paused if it was, and lets the UI know where it ended up
+    pub fn seek(&mut self, position: Duration) {
+        let track = match self.current() {
+            Some(t) => t,
+            None => return,
+        };
+
+        let paused = self.output.is_paused();
+        if self.play_from(track, position) {
+            if paused {
+                self.output.pause();
+            }
+            self.send(PlayerEvent::Position(self.position()));
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.output.stop();
+        self.shared = Arc::new(Mutex::new(Shared {
+            crossfade: self.crossfade,
+            prefer_gapless: self.prefer_gapless,
+            generation: self.generation,
+            ..Shared::default()
+        }));
+    }
+
+    pub fn pause(&mut self) {
+        self.output.pause();
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.output.set_volume(volume);
+    }
+
+    // Only picked up by tracks started after this
+    pub fn set_crossfade(&mut self, crossfade: Duration, prefer_gapless: bool) {
+        self.crossfade = crossfade;
+        self.prefer_gapless = prefer_gapless;
+    }
+
+    // Only picked up by tracks opened after this
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::output::PacedOutput;
+    use crate::storage::record::ReplayGain;
+    use crossbeam_channel::unbounded;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::PathBuf;
+    use std::thread;
+
+    // A scratch folder that is deleted again once the test is done with it
+    struct TestDir(PathBuf);
+
+    impl Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            if let Ok(()) = fs::remove_dir_all(&self.0) {}
+        }
+    }
+
+    fn test_dir(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("sonik-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    // A quiet stereo tone of the given length
+    fn wav_track(dir: &Path, name: &str, sample_rate: u32, millis: u32) -> Track {
+        let path = dir.join(name);
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..sample_rate * millis / 1000 {
+            let sample = ((i % 100) as i16 - 50) * 20;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        Track {
+            file_path: path.to_string_lossy().into_owned(),
+            title: name.to_string(),
+            album: "album".to_string(),
+            duration: millis,
+            ..Track::dummy()
+        }
+    }
+
+    fn start() -> (Sender<PlayerCommand>, Receiver<PlayerEvent>) {
+        let (command_tx, command_rx) = unbounded();
+        let (event_tx, event_rx) = unbounded();
+        let audio = Audio::new(Box::new(PacedOutput::null()), command_rx, event_tx);
+        thread::spawn(move || audio.run());
+
+        (command_tx, event_rx)
+    }
+
+    // Everything but the positions, up to the first finished or unplayable track
+    fn events_until_done(events: &Receiver<PlayerEvent>) -> Vec<String> {
+        let mut seen = Vec::new();
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+            match event {
+                PlayerEvent::Position(_) => continue,
+                PlayerEvent::Started(t, _) => seen.push(format!("started {}", t.title)),
+                PlayerEvent::Finished(t, _) => {
+                    seen.push(format!("finished {}", t.title));
+                    return seen;
+                }
+                PlayerEvent::Unplayable(t, reason) => {
+                    seen.push(format!("unplayable {}: {}", t.title, reason));
+                    return seen;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runs_straight_into_the_next_track() {
+        let dir = test_dir("gapless");
+        let first = wav_track(&dir, "first.wav", 44100, 300);
+        let second = wav_track(&dir, "second.wav", 44100, 200);
+
+        let (commands, events) = start();
+        commands.send(PlayerCommand::Play(first, 1)).unwrap();
+        commands.send(PlayerCommand::SetNext(Some(second))).unwrap();
+
+        assert_eq!(
+            events_until_done(&events),
+            vec!["started second.wav", "finished second.wav"]
+        );
+    }
+
+    #[test]
+    fn starts_a_next_track_in_another_format_itself() {
+        let dir = test_dir("formats");
+        let first = wav_track(&dir, "first.wav", 44100, 300);
+        let second = wav_track(&dir, "second.wav", 22050, 200);
+
+        let (commands, events) = start();
+        commands.send(PlayerCommand::Play(first, 1)).unwrap();
+        commands.send(PlayerCommand::SetNext(Some(second))).unwrap();
+
+        assert_eq!(
+            events_until_done(&events),
+            vec!["started second.wav", "finished second.wav"]
+        );
+    }
+
+    #[test]
+    fn finishes_when_nothing_is_lined_up() {
+        let dir = test_dir("finish");
+        let only = wav_track(&dir, "only.wav", 44100, 200);
+
+        let (commands, events) = start();
+        commands.send(PlayerCommand::Play(only, 1)).unwrap();
+
+        assert_eq!(events_until_done(&events), vec!["finished only.wav"]);
+    }
+
+    #[test]
+    fn reports_tracks_that_are_gone() {
+        let dir = test_dir("gone");
+        let gone = Track {
+            file_path: dir.join("gone.wav").to_string_lossy().into_owned(),
+            title: "gone.wav".to_string(),
+            ..Track::dummy()
+        };
+
+        let (commands, events) = start();
+        commands.send(PlayerCommand::Play(gone, 1)).unwrap();
+
+        assert_eq!(
+            events_until_done(&events),
+            vec!["unplayable gone.wav: file not found"]
+        );
+    }
+
+    #[test]
+    fn normalization_stays_under_the_peak() {
+        let normalization = Normalization {
+            mode: GainMode::Track,
+            preamp: 0.,
+            untagged: -6.,
+        };
+        let mut track = Track::dummy();
+        assert!((normalization.factor(&track) - 0.501).abs() < 0.001);
+
+        track.track_gain = Some(ReplayGain {
+            gain: 6.,
+            peak: Some(0.8),
+        });
+        assert!((normalization.factor(&track) - 1.25).abs() < 0.001);
+
+        let off = Normalization {
+            mode: GainMode::Off,
+            ..normalization
+        };
+        assert!((off.factor(&track) - 1.).abs() < f32::EPSILON);
+    }
+}
@@ -1,5 +1,7 @@
-use rand::thread_rng;
-use std::collections::VecDeque;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 
 use crate::storage::record::Track;
 
@@ -38,10 +40,216 @@ impl<T> LenAndSwap for VecDeque<T> {
 
 // Thanks to StackOverflow for the bit of code above.
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+impl RepeatMode {
+    // Cycles off -> one -> all -> off
+    pub fn next(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::One,
+            RepeatMode::One => RepeatMode::All,
+            RepeatMode::All => RepeatMode::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::All => "all",
+        }
+    }
+}
+
+// What to do once the last track in the queue has finished
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueEnd {
+    #[default]
+    Stop,
+    // Play everything since the queue last ran out again
+    Repeat,
+    // Go on with random tracks from the library
+    Random,
+    // Go on with the next album by the artist of the last track
+    NextAlbum,
+    Quit,
+}
+
+impl QueueEnd {
+    pub fn next(self) -> QueueEnd {
+        match self {
+            QueueEnd::Stop => QueueEnd::Repeat,
+            QueueEnd::Repeat => QueueEnd::Random,
+            QueueEnd::Random => QueueEnd::NextAlbum,
+            QueueEnd::NextAlbum => QueueEnd::Quit,
+            QueueEnd::Quit => QueueEnd::Stop,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            QueueEnd::Stop => "stop",
+            QueueEnd::Repeat => "repeat",
+            QueueEnd::Random => "random tracks",
+            QueueEnd::NextAlbum => "next album",
+            QueueEnd::Quit => "quit",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShuffleMode {
+    #[default]
+    Random,
+    // Albums are shuffled, tracks within them play in order
+    Album,
+    // The same artist doesn't come up again within a few tracks
+    ArtistSpread,
+    // Rarely played and highly rated tracks tend to come first
+    Weighted,
+}
+
+impl ShuffleMode {
+    pub fn next(self) -> ShuffleMode {
+        match self {
+            ShuffleMode::Random => ShuffleMode::Album,
+            ShuffleMode::Album => ShuffleMode::ArtistSpread,
+            ShuffleMode::ArtistSpread => ShuffleMode::Weighted,
+            ShuffleMode::Weighted => ShuffleMode::Random,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ShuffleMode::Random => "random",
+            ShuffleMode::Album => "album",
+            ShuffleMode::ArtistSpread => "artist spread",
+            ShuffleMode::Weighted => "weighted",
+        }
+    }
+}
+
+// Splits tracks into groups sharing a key, in order of first appearance,
+// with the tracks in each group keeping their order
+fn group_by<F>(tracks: Vec<Track>, key: F) -> Vec<Vec<Track>>
+where
+    F: Fn(&Track) -> String,
+{
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<Vec<Track>> = Vec::new();
+
+    for track in tracks {
+        let next = groups.len();
+        let idx = *positions.entry(key(&track)).or_insert(next);
+        if idx == next {
+            groups.push(Vec::new());
+        }
+        groups[idx].push(track);
+    }
+
+    groups
+}
+
+fn album_shuffle<R: Rng>(tracks: Vec<Track>, rng: &mut R) -> Vec<Track> {
+    let mut albums = group_by(tracks, |t| format!("{}\0{}", t.album_artist, t.album));
+    albums.shuffle(rng);
+
+    // An earlier shuffle may have mixed up the tracks of an album
+    for album in &mut albums {
+        album.sort_by_key(|t| t.track_num);
+    }
+
+    albums.into_iter().flatten().collect()
+}
+
+// Deals tracks out one artist at a time, always picking from the artist with
+// the most tracks left among those not heard in the last `spread` tracks, so
+// that no single artist is left bunched up at the end
+fn artist_spread_shuffle<R: Rng>(tracks: Vec<Track>, spread: usize, rng: &mut R) -> Vec<Track> {
+    let total = tracks.len();
+    let mut artists = group_by(tracks, |t| t.artist.to_lowercase());
+    artists.shuffle(rng);
+    for artist in &mut artists {
+        artist.shuffle(rng);
+    }
+
+    let mut last_picked: Vec<Option<usize>> = vec![None; artists.len()];
+    let mut shuffled = Vec::with_capacity(total);
+
+    while shuffled.len() < total {
+        let pos = shuffled.len();
+        let mut pick: Option<usize> = None;
+
+        for (idx, artist) in artists.iter().enumerate() {
+            let recent = matches!(last_picked[idx], Some(last) if pos - last <= spread);
+            if artist.is_empty() || recent {
+                continue;
+            }
+            match pick {
+                Some(p) if artists[p].len() >= artist.len() => {}
+                _ => pick = Some(idx),
+            }
+        }
+
+        // When every artist left was heard recently, go with
+        // the one that was heard the longest time ago
+        let pick = pick.unwrap_or_else(|| {
+            (0..artists.len())
+                .filter(|idx| !artists[*idx].is_empty())
+                .min_by_key(|idx| last_picked[*idx])
+                .unwrap()
+        });
+
+        shuffled.push(artists[pick].pop().unwrap());
+        last_picked[pick] = Some(pos);
+    }
+
+    shuffled
+}
+
+// Weighted random ordering, each track getting a random key of u^(1/weight)
+// and heavier tracks tending to end up with the larger keys
+fn weighted_shuffle<R, F>(tracks: Vec<Track>, weight: F, rng: &mut R) -> Vec<Track>
+where
+    R: Rng,
+    F: Fn(&Track) -> f64,
+{
+    let mut keyed = tracks
+        .into_iter()
+        .map(|t| {
+            let w = weight(&t).max(0.001);
+            (rng.gen::<f64>().powf(1. / w), t)
+        })
+        .collect::<Vec<(f64, Track)>>();
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    keyed.into_iter().map(|(_, t)| t).collect()
+}
+
+// Everything needed to put the queue back the way it was
+pub struct QueueSnapshot {
+    pub tracks: VecDeque<Track>,
+    total_time: u32,
+    unshuffled: Option<Vec<Track>>,
+}
+
 #[derive(Default)]
 pub struct SonikQueue {
     pub tracks: VecDeque<Track>,
     pub total_time: u32,
+    pub repeat: RepeatMode,
+    pub shuffle_mode: ShuffleMode,
+    // How many tracks apart the artist spread shuffle keeps an artist
+    pub spread: usize,
+    // The order from before the first shuffle, to be able to go back to it
+    unshuffled: Option<Vec<Track>>,
 }
 
 impl SonikQueue {
@@ -49,6 +257,10 @@ impl SonikQueue {
         SonikQueue {
             tracks: VecDeque::<Track>::new(),
             total_time: 0,
+            repeat: RepeatMode::Off,
+            shuffle_mode: ShuffleMode::Random,
+            spread: 3,
+            unshuffled: None,
         }
     }
 
@@ -65,10 +277,66 @@ impl SonikQueue {
     pub fn clear(&mut self) {
         self.tracks.clear();
         self.total_time = 0;
+        self.unshuffled = None;
+    }
+
+    // Shuffles in the current mode; the weight of each
+    // track only matters for the weighted shuffle
+    pub fn shuffle<F>(&mut self, weight: F)
+    where
+        F: Fn(&Track) -> f64,
+    {
+        if self.unshuffled.is_none() {
+            self.unshuffled = Some(self.tracks.iter().cloned().collect());
+        }
+
+        let mut rng = thread_rng();
+        let tracks = self.tracks.drain(..).collect::<Vec<Track>>();
+        let shuffled = match self.shuffle_mode {
+            ShuffleMode::Random => {
+                let mut tracks = VecDeque::from(tracks);
+                shuffle(&mut tracks, &mut rng);
+                tracks.into_iter().collect()
+            }
+            ShuffleMode::Album => album_shuffle(tracks, &mut rng),
+            ShuffleMode::ArtistSpread => artist_spread_shuffle(tracks, self.spread, &mut rng),
+            ShuffleMode::Weighted => weighted_shuffle(tracks, weight, &mut rng),
+        };
+        self.tracks = shuffled.into();
     }
 
-    pub fn shuffle(&mut self) {
-        shuffle(&mut self.tracks, thread_rng());
+    // Puts the queue back in the order it was in before it was first
+    // shuffled. Tracks removed since stay gone and tracks added since
+    // stay at the end. Returns false if the queue wasn't shuffled.
+    pub fn unshuffle(&mut self) -> bool {
+        let original = match self.unshuffled.take() {
+            Some(o) => o,
+            None => return false,
+        };
+
+        // The same file can be queued more than once, so keep every position
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        for (idx, track) in self.tracks.iter().enumerate().rev() {
+            positions
+                .entry(track.file_path.clone())
+                .or_default()
+                .push(idx);
+        }
+
+        let mut current = self
+            .tracks
+            .drain(..)
+            .map(Some)
+            .collect::<Vec<Option<Track>>>();
+        for track in original {
+            let idx = positions.get_mut(&track.file_path).and_then(|p| p.pop());
+            if let Some(track) = idx.and_then(|i| current[i].take()) {
+                self.tracks.push_back(track);
+            }
+        }
+        self.tracks.extend(current.into_iter().flatten());
+
+        true
     }
 
     pub fn is_empty(&self) -> bool {
@@ -76,9 +344,218 @@ impl SonikQueue {
     }
 
     pub fn take(&mut self) -> Track {
-        let track = self.tracks.pop_front().unwrap();
+        self.take_at(0).unwrap()
+    }
+
+    // Takes a track out of the queue to be played
+    pub fn take_at(&mut self, idx: usize) -> Option<Track> {
+        let track = self.remove(idx)?;
+
+        // When repeating the whole queue, played tracks go around again
+        if self.repeat == RepeatMode::All {
+            self.add(track.clone());
+        }
+
+        Some(track)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            tracks: self.tracks.clone(),
+            total_time: self.total_time,
+            unshuffled: self.unshuffled.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: QueueSnapshot) {
+        self.tracks = snapshot.tracks;
+        self.total_time = snapshot.total_time;
+        self.unshuffled = snapshot.unshuffled;
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Option<Track> {
+        let track = self.tracks.remove(idx)?;
         self.total_time -= &track.duration;
 
-        track
+        Some(track)
+    }
+
+    // Drops every track that shouldn't be kept, returning how many went
+    pub fn retain<F: Fn(&Track) -> bool>(&mut self, keep: F) -> usize {
+        let before = self.tracks.len();
+        self.tracks.retain(|t| keep(t));
+        self.total_time = self.tracks.iter().map(|t| t.duration).sum();
+
+        before - self.tracks.len()
+    }
+
+    // Reordering doesn't change the total time, as nothing leaves the queue
+    pub fn move_up(&mut self, idx: usize) -> bool {
+        if idx == 0 || idx >= self.tracks.len() {
+            return false;
+        }
+
+        self.tracks.swap(idx, idx - 1);
+        true
+    }
+
+    pub fn move_down(&mut self, idx: usize) -> bool {
+        if idx + 1 >= self.tracks.len() {
+            return false;
+        }
+
+        self.tracks.swap(idx, idx + 1);
+        true
+    }
+
+    pub fn move_to_front(&mut self, idx: usize) -> bool {
+        match self.tracks.remove(idx) {
+            Some(track) => {
+                self.tracks.push_front(track);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, duration: u32) -> Track {
+        Track {
+            file_path: path.to_string(),
+            title: path.to_string(),
+            duration,
+            ..Track::dummy()
+        }
+    }
+
+    fn queue_of(paths: &[&str]) -> SonikQueue {
+        let mut queue = SonikQueue::new();
+        for path in paths {
+            queue.add(track(path, 10));
+        }
+        queue
+    }
+
+    fn paths(queue: &SonikQueue) -> Vec<String> {
+        queue.tracks.iter().map(|t| t.file_path.clone()).collect()
+    }
+
+    #[test]
+    fn total_time_follows_adds_and_removes() {
+        let mut queue = SonikQueue::new();
+        queue.add(track("a", 10));
+        queue.add_to_front(track("b", 20));
+        assert_eq!(queue.total_time, 30);
+        assert_eq!(paths(&queue), vec!["b", "a"]);
+
+        assert_eq!(queue.remove(0).unwrap().file_path, "b");
+        assert_eq!(queue.total_time, 10);
+        assert!(queue.remove(5).is_none());
+    }
+
+    #[test]
+    fn retain_drops_tracks_and_recounts_the_time() {
+        let mut queue = SonikQueue::new();
+        queue.add(track("a", 10));
+        queue.add(track("b", 20));
+        queue.add(track("a", 10));
+
+        assert_eq!(queue.retain(|t| t.file_path != "a"), 2);
+        assert_eq!(paths(&queue), vec!["b"]);
+        assert_eq!(queue.total_time, 20);
+    }
+
+    #[test]
+    fn take_at_only_keeps_tracks_when_repeating_all() {
+        let mut queue = queue_of(&["a", "b", "c"]);
+        assert_eq!(queue.take().file_path, "a");
+        assert_eq!(paths(&queue), vec!["b", "c"]);
+
+        queue.repeat = RepeatMode::All;
+        assert_eq!(queue.take_at(1).unwrap().file_path, "c");
+        assert_eq!(paths(&queue), vec!["b", "c"]);
+        assert_eq!(queue.take().file_path, "b");
+        assert_eq!(paths(&queue), vec!["c", "b"]);
+        assert_eq!(queue.total_time, 20);
+    }
+
+    #[test]
+    fn unshuffle_restores_the_order_around_changes() {
+        let mut queue = queue_of(&["a", "b", "c", "d", "e"]);
+        assert!(!queue.unshuffle());
+
+        queue.shuffle(|_| 1.);
+        queue.retain(|t| t.file_path != "c");
+        queue.add(track("f", 10));
+
+        assert!(queue.unshuffle());
+        assert_eq!(paths(&queue), vec!["a", "b", "d", "e", "f"]);
+    }
+
+    #[test]
+    fn album_shuffle_keeps_albums_together_in_order() {
+        let mut queue = SonikQueue::new();
+        queue.shuffle_mode = ShuffleMode::Album;
+        for album in &["x", "y", "z"] {
+            for num in (1..=4).rev() {
+                queue.add(Track {
+                    album: album.to_string(),
+                    track_num: num,
+                    ..track(&format!("{}{}", album, num), 10)
+                });
+            }
+        }
+
+        queue.shuffle(|_| 1.);
+        for album in queue.tracks.iter().collect::<Vec<&Track>>().chunks(4) {
+            assert!(album.iter().all(|t| t.album == album[0].album));
+            let nums: Vec<u32> = album.iter().map(|t| t.track_num).collect();
+            assert_eq!(nums, vec![1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn artist_spread_keeps_artists_apart() {
+        let mut queue = SonikQueue::new();
+        queue.shuffle_mode = ShuffleMode::ArtistSpread;
+        queue.spread = 2;
+        for artist in &["a", "b", "c", "d"] {
+            for num in 0..3 {
+                queue.add(Track {
+                    artist: artist.to_string(),
+                    ..track(&format!("{}{}", artist, num), 10)
+                });
+            }
+        }
+
+        queue.shuffle(|_| 1.);
+        let artists: Vec<&str> = queue.tracks.iter().map(|t| t.artist.as_str()).collect();
+        for window in artists.windows(3) {
+            assert!(window[0] != window[1] && window[0] != window[2]);
+        }
+    }
+
+    #[test]
+    fn moves_stay_within_the_queue() {
+        let mut queue = queue_of(&["a", "b", "c"]);
+        assert!(!queue.move_up(0));
+        assert!(!queue.move_down(2));
+        assert!(!queue.move_to_front(3));
+
+        assert!(queue.move_up(1));
+        assert_eq!(paths(&queue), vec!["b", "a", "c"]);
+        assert!(queue.move_down(0));
+        assert_eq!(paths(&queue), vec!["a", "b", "c"]);
+        assert!(queue.move_to_front(2));
+        assert_eq!(paths(&queue), vec!["c", "a", "b"]);
+        assert_eq!(queue.total_time, 30);
     }
 }
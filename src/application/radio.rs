@@ -0,0 +1,95 @@
+use std::collections::{HashSet, VecDeque};
+
+use chrono::Local;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+use crate::storage::plays::PlayLog;
+use crate::storage::record::{Artist, Track};
+use crate::storage::terms::SearchQuery;
+
+// Keeps the queue topped up with random tracks from the library,
+// optionally only ones matching a query
+pub struct Radio {
+    pub query: String,
+    filter: Option<SearchQuery>,
+    // Tracks picked or played recently, oldest first
+    recent: VecDeque<String>,
+    window: usize,
+}
+
+impl Radio {
+    // An empty query plays the whole library
+    pub fn new(query: &str, window: usize) -> Result<Radio, ()> {
+        let query = query.trim();
+        let filter = if query.is_empty() {
+            None
+        } else {
+            Some(SearchQuery::new(query).ok_or(())?)
+        };
+
+        Ok(Radio {
+            query: query.to_string(),
+            filter,
+            recent: VecDeque::new(),
+            window,
+        })
+    }
+
+    pub fn remember(&mut self, track: &Track) {
+        if let Some(idx) = self.recent.iter().position(|p| *p == track.file_path) {
+            self.recent.remove(idx);
+        }
+        self.recent.push_back(track.file_path.clone());
+
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+    }
+
+    // Picks up to `count` tracks that haven't come up within the window
+    pub fn pick(&mut self, library: &[Artist], plays: &PlayLog, count: usize) -> Vec<Track> {
+        let now = Local::now().timestamp();
+        let matching = library
+            .iter()
+            .flat_map(|artist| artist.albums.iter())
+            .flat_map(|album| album.tracks.iter())
+            .filter(|track| match &self.filter {
+                Some(filter) => filter.matches(track, plays, now),
+                None => true,
+            })
+            .collect::<Vec<&Track>>();
+
+        if matching.is_empty() {
+            return Vec::new();
+        }
+
+        // A window bigger than what there is to pick from would
+        // leave nothing, so let the oldest tracks come back early
+        let mut candidates = Vec::new();
+        while candidates.is_empty() {
+            let recent = self.recent.iter().collect::<HashSet<&String>>();
+            candidates = matching
+                .iter()
+                .filter(|track| !recent.contains(&track.file_path))
+                .cloned()
+                .collect::<Vec<&Track>>();
+
+            if candidates.is_empty() {
+                let keep = self.recent.len() / 2;
+                let forget = self.recent.len() - keep;
+                self.recent.drain(..forget);
+            }
+        }
+
+        let picked = candidates
+            .choose_multiple(&mut thread_rng(), count)
+            .map(|track| (*track).clone())
+            .collect::<Vec<Track>>();
+        for track in &picked {
+            self.remember(track);
+        }
+
+        picked
+    }
+}
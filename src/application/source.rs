@@ -0,0 +1,295 @@
+use std::f32::consts::FRAC_PI_2;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use rodio::{Decoder, Source};
+
+use crate::application::player::PlayerEvent;
+use crate::storage::record::Track;
+
+// A decoded track, opened part of the way through if need be
+pub struct TrackSource {
+    decoder: Decoder<BufReader<File>>,
+    pub track: Track,
+    // Samples skipped to get to the starting position
+    pub start: u64,
+    // What every sample is multiplied by to even out the loudness
+    gain: f32,
+}
+
+impl TrackSource {
+    // rodio's decoders can't seek, so everything before
+    // the position is decoded and thrown away
+    pub fn open(track: &Track, position: Duration, gain: f32) -> Result<TrackSource, ()> {
+        let file = File::open(&track.file_path).map_err(|_| ())?;
+        let decoder = Decoder::new(BufReader::new(file)).map_err(|_| ())?;
+
+        let mut source = TrackSource {
+            decoder,
+            track: track.clone(),
+            start: 0,
+            gain,
+        };
+
+        let skip = source.samples_per_sec() * position.as_millis() as u64 / 1000;
+        while source.start < skip && source.decoder.next().is_some() {
+            source.start += 1;
+        }
+
+        Ok(source)
+    }
+
+    pub fn samples_per_sec(&self) -> u64 {
+        u64::from(self.decoder.sample_rate()) * u64::from(self.decoder.channels())
+    }
+
+    // Tracks can only run into each other if nothing needs converting
+    fn same_format(&self, other: &TrackSource) -> bool {
+        self.decoder.sample_rate() == other.decoder.sample_rate()
+            && self.decoder.channels() == other.decoder.channels()
+    }
+
+    // Not every decoder knows how long the track is, so the tags are the fallback
+    fn total_samples(&self) -> Option<u64> {
+        let millis = match self.decoder.total_duration() {
+            Some(d) => d.as_millis() as u64,
+            None if self.track.duration > 0 => u64::from(self.track.duration),
+            None => return None,
+        };
+
+        Some(self.samples_per_sec() * millis / 1000)
+    }
+
+    fn same_album(&self, other: &TrackSource) -> bool {
+        !self.track.album.is_empty()
+            && self.track.album == other.track.album
+            && self.track.album_artist == other.track.album_artist
+    }
+}
+
+impl Iterator for TrackSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.decoder.next()?;
+        if (self.gain - 1.).abs() < f32::EPSILON {
+            return Some(sample);
+        }
+
+        let scaled = f32::from(sample) * self.gain;
+        Some(scaled.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16)
+    }
+}
+
+impl Source for TrackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.decoder.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.decoder.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.decoder.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.decoder.total_duration()
+    }
+}
+
+// What the audio thread and the chain being played both need to see
+#[derive(Default)]
+pub struct Shared {
+    // The track coming out of the speakers
+    pub track: Option<Track>,
+    // Opened ahead of time, to carry straight on with once the track ends
+    pub next: Option<TrackSource>,
+    // How long the end of a track overlaps the start of the next one
+    pub crossfade: Duration,
+    // Keeps tracks from the same album from overlapping
+    pub prefer_gapless: bool,
+    // The play or stop this chain came from, sent back with its events
+    pub generation: u64,
+}
+
+// Plays tracks one after the other without a gap, moving on to the next
+// track in the shared slot the moment the current one runs out, or
+// fading into it towards the end when crossfading
+pub struct Chain {
+    current: TrackSource,
+    shared: Arc<Mutex<Shared>>,
+    // Samples played of the current track
+    played: Arc<AtomicU64>,
+    events: Sender<PlayerEvent>,
+    // The track being faded out, and how far along the fade is
+    outgoing: Option<TrackSource>,
+    fade_len: u64,
+    fade_pos: u64,
+    // How far into the current track to start fading into the next one
+    fade_at: Option<u64>,
+}
+
+impl Chain {
+    pub fn new(
+        current: TrackSource,
+        shared: Arc<Mutex<Shared>>,
+        played: Arc<AtomicU64>,
+        events: Sender<PlayerEvent>,
+    ) -> Chain {
+        played.store(current.start, Ordering::Relaxed);
+        let mut chain = Chain {
+            current,
+            shared,
+            played,
+            events,
+            outgoing: None,
+            fade_len: 0,
+            fade_pos: 0,
+            fade_at: None,
+        };
+        chain.plan_fade();
+
+        chain
+    }
+
+    // Works out where the current track should start fading out, if at all
+    fn plan_fade(&mut self) {
+        let crossfade = self.shared.lock().unwrap().crossfade;
+        self.fade_len = self.current.samples_per_sec() * crossfade.as_millis() as u64 / 1000;
+        self.fade_at = match self.current.total_samples() {
+            Some(total) if self.fade_len > 0 && total > self.fade_len => {
+                Some(total - self.fade_len)
+            }
+            _ => None,
+        };
+    }
+
+    // Moves on to the next track while the current one is faded out
+    // underneath it, as long as the two can be mixed
+    fn start_fade(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        let next = match shared.next.take() {
+            Some(n) => n,
+            None => return,
+        };
+
+        let gapless = shared.prefer_gapless && next.same_album(&self.current);
+        if gapless || !next.same_format(&self.current) {
+            shared.next = Some(next);
+            return;
+        }
+
+        shared.track = Some(next.track.clone());
+        let generation = shared.generation;
+        drop(shared);
+
+        self.played.store(next.start, Ordering::Relaxed);
+        let started = PlayerEvent::Started(next.track.clone(), generation);
+        if let Ok(()) = self.events.send(started) {}
+        self.outgoing = Some(std::mem::replace(&mut self.current, next));
+        self.fade_pos = 0;
+        self.plan_fade();
+    }
+
+    // Mixes in the track being faded out, using an equal power curve
+    // so the overall loudness stays about the same through the fade
+    fn mix(&mut self, sample: i16) -> i16 {
+        let outgoing = match &mut self.outgoing {
+            Some(o) => o,
+            None => return sample,
+        };
+
+        let old = match outgoing.next() {
+            Some(s) if self.fade_pos < self.fade_len => s,
+            _ => {
+                self.outgoing = None;
+                return sample;
+            }
+        };
+
+        let t = self.fade_pos as f32 / self.fade_len as f32 * FRAC_PI_2;
+        self.fade_pos += 1;
+
+        let mixed = f32::from(sample) * t.sin() + f32::from(old) * t.cos();
+        mixed.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+    }
+
+    // Swaps in the next track, unless there is none or it would need
+    // converting differently, in which case the chain ends here and the
+    // audio thread starts a new one
+    fn advance(&mut self) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        let next = match shared.next.take() {
+            Some(n) => n,
+            None => return false,
+        };
+
+        if !next.same_format(&self.current) {
+            shared.next = Some(next);
+            return false;
+        }
+
+        shared.track = Some(next.track.clone());
+        let generation = shared.generation;
+        drop(shared);
+
+        self.played.store(next.start, Ordering::Relaxed);
+        let started = PlayerEvent::Started(next.track.clone(), generation);
+        if let Ok(()) = self.events.send(started) {}
+        self.current = next;
+        self.plan_fade();
+
+        true
+    }
+}
+
+impl Iterator for Chain {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        loop {
+            if let Some(at) = self.fade_at {
+                if self.played.load(Ordering::Relaxed) >= at {
+                    // Only tried the once, a track lined up any later
+                    // than this just follows on without a fade
+                    self.fade_at = None;
+                    self.start_fade();
+                }
+            }
+
+            if let Some(sample) = self.current.next() {
+                self.played.fetch_add(1, Ordering::Relaxed);
+                return Some(self.mix(sample));
+            }
+
+            if !self.advance() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Source for Chain {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.current.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.current.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.current.sample_rate()
+    }
+
+    // Unknown, as more tracks may be added on
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
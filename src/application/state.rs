@@ -1,14 +1,49 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::{Receiver, Sender};
-use rodio::{Device, Sink};
 
-use crate::application::queue::SonikQueue;
+use crate::application::config::Config;
+use crate::application::player::{PlayerCommand, PlayerEvent};
+use crate::application::queue::{QueueEnd, QueueSnapshot, RepeatMode, SonikQueue};
+use crate::application::radio::Radio;
 use crate::storage::database::search as db_search;
 use crate::storage::database::{EngineGroup, SearchResult};
-use crate::storage::record::{Album, Artist, Media, Stats, Track};
+use crate::storage::index::Hit;
+use crate::storage::playlist::{
+    append_to_playlist, list_playlists, playlist_path, read_playlist, resolve_playlist,
+    save_playlist_order, write_playlist, Playlist, SavedPlaylist,
+};
+use crate::storage::plays::PlayLog;
+use crate::storage::ratings::{Ratings, MAX_RATING};
+use crate::storage::record::{Album, Artist, Media, Record, Stats, Track};
+use crate::storage::session::Session;
+use crate::storage::smart::SmartPlaylist;
 use crate::storage::terms::SearchQuery;
+use crate::storage::text::normalize;
+
+// How many previously played tracks are remembered
+const HISTORY_LIMIT: usize = 100;
+
+// How long a status message stays up
+const STATUS_DURATION: Duration = Duration::from_secs(5);
+
+// How much the volume keys change the volume by
+const VOLUME_STEP: f32 = 0.05;
+
+// How many tracks radio mode keeps lined up in the queue
+const RADIO_QUEUE_LENGTH: usize = 3;
+
+// Undo history is cut short at whichever of these limits comes first,
+// as every step keeps a full copy of the queue
+const UNDO_LIMIT: usize = 50;
+const UNDO_TRACK_LIMIT: usize = 100_000;
+
+// How many tracks are kept for repeating the queue, as radio mode
+// can keep it from ever running out; the oldest are dropped first
+const QUEUE_RUN_LIMIT: usize = 10_000;
 
 // Tabs only need name and ordering information
 pub struct TabsState<'a> {
@@ -38,6 +73,9 @@ impl<'a> TabsState<'a> {
 pub struct ListState<I> {
     pub items: Vec<I>,
     pub selected: usize,
+    pub filter: String,
+    // The full list is put aside while a filter narrows down the items
+    unfiltered: Option<Vec<I>>,
 }
 
 impl<I> ListState<I>
@@ -48,6 +86,16 @@ where
         ListState {
             items: items.to_vec(),
             selected: 0,
+            filter: String::new(),
+            unfiltered: None,
+        }
+    }
+
+    // Every item, whether or not it is currently filtered out
+    pub fn all(&self) -> &[I] {
+        match &self.unfiltered {
+            Some(items) => items,
+            None => &self.items,
         }
     }
 
@@ -64,12 +112,81 @@ where
     }
 }
 
+impl<I> ListState<I>
+where
+    I: std::clone::Clone + Record,
+{
+    // Selects the first item whose name starts with the prefix
+    fn jump(&mut self, prefix: &str) {
+        let prefix = normalize(prefix);
+        if let Some(idx) = self
+            .items
+            .iter()
+            .position(|i| normalize(i.name()).starts_with(&prefix))
+        {
+            self.selected = idx;
+        }
+    }
+
+    // Narrows the items down to the ones containing the filter text; a
+    // filter that matches nothing is cleared instead, as the rest of the
+    // library view expects every column to have an item. Returns whether
+    // anything matched.
+    fn apply_filter(&mut self, filter: &str) -> bool {
+        if filter.is_empty() {
+            self.clear_filter();
+            return true;
+        }
+
+        let needle = normalize(filter);
+        let matches = self
+            .all()
+            .iter()
+            .filter(|i| normalize(i.name()).contains(&needle))
+            .cloned()
+            .collect::<Vec<I>>();
+
+        if matches.is_empty() {
+            self.clear_filter();
+            return false;
+        }
+
+        if self.unfiltered.is_none() {
+            self.unfiltered = Some(std::mem::replace(&mut self.items, matches));
+        } else {
+            self.items = matches;
+        }
+        self.filter = filter.to_string();
+        self.selected = 0;
+        true
+    }
+
+    fn clear_filter(&mut self) {
+        if let Some(all) = self.unfiltered.take() {
+            // Keep the same item selected in the full list
+            let name = self.items[self.selected].name().to_string();
+            self.selected = all.iter().position(|i| i.name() == name).unwrap_or(0);
+            self.items = all;
+        }
+        self.filter = String::new();
+    }
+}
+
+// Text being typed into the active library column
+pub enum LibraryInput {
+    Jump(String),
+    Filter(String),
+}
+
 // Associates all of the columns together by artist and album selection
 pub struct LibraryCols {
     pub artists: ListState<Artist>,
     pub albums: ListState<Album>,
     pub tracks: ListState<Track>,
     pub current_active: usize,
+    pub input: Option<LibraryInput>,
+    // Set while the filter being typed doesn't match anything
+    pub no_matches: bool,
 }
 
 impl LibraryCols {
@@ -88,88 +205,211 @@ impl LibraryCols {
     pub fn on_up(&mut self) {
         // List states need to be refreshed when scrolling through each column
         match self.current_active {
-            0 => {
-                self.artists.select_previous();
-                self.albums = ListState::new(&self.artists.items[self.artists.selected].albums);
-                self.tracks = ListState::new(&self.albums.items[self.albums.selected].tracks);
-            }
-            1 => {
-                self.albums.select_previous();
-                self.tracks = ListState::new(&self.albums.items[self.albums.selected].tracks);
-            }
+            0 => self.artists.select_previous(),
+            1 => self.albums.select_previous(),
             2 => self.tracks.select_previous(),
             _ => {}
         };
+        self.refresh_columns();
     }
 
     pub fn on_down(&mut self) {
         match self.current_active {
-            0 => {
-                self.artists.select_next();
-                self.albums = ListState::new(&self.artists.items[self.artists.selected].albums);
-                self.tracks = ListState::new(&self.albums.items[self.albums.selected].tracks);
-            }
-            1 => {
-                self.albums.select_next();
-                self.tracks = ListState::new(&self.albums.items[self.albums.selected].tracks);
-            }
+            0 => self.artists.select_next(),
+            1 => self.albums.select_next(),
             2 => self.tracks.select_next(),
             _ => {}
         };
+        self.refresh_columns();
+    }
+
+    // Columns to the right of the active one follow its selection
+    fn refresh_columns(&mut self) {
+        if self.current_active == 0 {
+            self.albums = ListState::new(&self.artists.items[self.artists.selected].albums);
+        }
+        if self.current_active <= 1 {
+            self.tracks = ListState::new(&self.albums.items[self.albums.selected].tracks);
+        }
+    }
+
+    pub fn start_jump(&mut self) {
+        self.input = Some(LibraryInput::Jump(String::new()));
+    }
+
+    pub fn start_filter(&mut self) {
+        // Pick up where the last filter on this column left off
+        let filter = match self.current_active {
+            0 => self.artists.filter.clone(),
+            1 => self.albums.filter.clone(),
+            _ => self.tracks.filter.clone(),
+        };
+        self.input = Some(LibraryInput::Filter(filter));
+    }
+
+    pub fn push_input(&mut self, c: char) {
+        match &mut self.input {
+            Some(LibraryInput::Jump(s)) | Some(LibraryInput::Filter(s)) => s.push(c),
+            None => return,
+        }
+        self.update_input();
+    }
+
+    pub fn pop_input(&mut self) {
+        match &mut self.input {
+            Some(LibraryInput::Jump(s)) | Some(LibraryInput::Filter(s)) => {
+                s.pop();
+            }
+            None => return,
+        }
+        self.update_input();
+    }
+
+    // The filter or jump stays in effect once typing is finished
+    pub fn finish_input(&mut self) {
+        self.input = None;
+        self.no_matches = false;
+    }
+
+    // Leaves the selection where a jump put it, but drops a filter
+    pub fn cancel_input(&mut self) {
+        self.no_matches = false;
+        if let Some(LibraryInput::Filter(_)) = self.input.take() {
+            match self.current_active {
+                0 => self.artists.clear_filter(),
+                1 => self.albums.clear_filter(),
+                _ => self.tracks.clear_filter(),
+            }
+            self.refresh_columns();
+        }
+    }
+
+    fn update_input(&mut self) {
+        match &self.input {
+            Some(LibraryInput::Jump(s)) => match self.current_active {
+                0 => self.artists.jump(s),
+                1 => self.albums.jump(s),
+                _ => self.tracks.jump(s),
+            },
+            Some(LibraryInput::Filter(s)) => {
+                let matched = match self.current_active {
+                    0 => self.artists.apply_filter(s),
+                    1 => self.albums.apply_filter(s),
+                    _ => self.tracks.apply_filter(s),
+                };
+                self.no_matches = !matched;
+            }
+            None => {}
+        }
+        self.refresh_columns();
     }
 }
 
-// t - track, b - bool, p - play/pause
-// Sends information to the UI when audio is not playing,
-// and also receives tracks from the queue as well as play/pause events
-pub struct Audio {
-    pub device: Device,
-    pub sink: Sink,
-    pub trx: Receiver<Track>,
-    pub btx: Sender<bool>,
-    pub prx: Receiver<bool>,
+// Things that are typed in at the bottom of the screen
+pub enum PromptKind {
+    SavePlaylist,
+    NewPlaylist,
+    RenamePlaylist,
+    DeletePlaylist,
+    Radio,
+    Seek,
 }
 
-impl Audio {
-    pub fn new(
-        device: Device,
-        trx: Receiver<Track>,
-        btx: Sender<bool>,
-        prx: Receiver<bool>,
-    ) -> Audio {
-        Audio {
-            sink: Sink::new(&device),
-            device,
-            trx,
-            btx,
-            prx,
+impl PromptKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PromptKind::SavePlaylist => "save queue as",
+            PromptKind::NewPlaylist => "new playlist",
+            PromptKind::RenamePlaylist => "rename playlist to",
+            PromptKind::DeletePlaylist => "delete playlist? type y to confirm",
+            PromptKind::Radio => "radio query (empty for everything)",
+            PromptKind::Seek => "seek to (percent or m:ss)",
+        }
+    }
+}
+
+pub struct Prompt {
+    pub kind: PromptKind,
+    pub text: String,
+}
+
+// Reads a position typed in as a percentage of the track, like "40%",
+// or as a timestamp, like "83", "1:23" or "1:02:03"
+pub fn parse_position(text: &str, duration: Duration) -> Option<Duration> {
+    let text = text.trim();
+
+    if let Some(percent) = text.strip_suffix('%') {
+        let percent = percent.trim().parse::<f64>().ok()?;
+        if !(0. ..=100.).contains(&percent) || duration == Duration::from_secs(0) {
+            return None;
+        }
+        let millis = duration.as_millis() as f64 * percent / 100.;
+        return Some(Duration::from_millis(millis as u64));
+    }
+
+    let mut seconds: u64 = 0;
+    for part in text.split(':') {
+        seconds = seconds * 60 + part.trim().parse::<u64>().ok()?;
+    }
+
+    Some(Duration::from_secs(seconds))
+}
+
+// Keeps track of how far into the current track playback is,
+// not counting the time spent paused
+#[derive(Default)]
+pub struct PlaybackClock {
+    started: Option<Instant>,
+    elapsed: Duration,
+}
+
+impl PlaybackClock {
+    pub fn new() -> PlaybackClock {
+        PlaybackClock {
+            started: None,
+            elapsed: Duration::from_secs(0),
         }
     }
 
-    pub fn play(&mut self, track: Track) {
-        // The clear function does not work for rodio::Sink, so the
-        // sink field is just reassigned and it works just as well
-        self.sink = Sink::new(&self.device);
-        let file = File::open(&track.file_path).unwrap();
-        let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-        self.sink.append(source);
+    pub fn start_at(&mut self, position: Duration) {
+        self.elapsed = position;
+        self.started = Some(Instant::now());
+    }
+
+    // Stopped at the position, waiting for pause_play to carry on
+    pub fn pause_at(&mut self, position: Duration) {
+        self.elapsed = position;
+        self.started = None;
     }
 
-    // Notify the UI that there is no audio playing
-    pub fn notify(&mut self) {
-        if let Ok(()) = self.btx.send(true) {}
+    pub fn is_running(&self) -> bool {
+        self.started.is_some()
     }
 
     pub fn pause_play(&mut self) {
-        if self.sink.is_paused() {
-            self.sink.play();
-        } else {
-            self.sink.pause();
+        match self.started.take() {
+            Some(started) => self.elapsed += started.elapsed(),
+            None => self.started = Some(Instant::now()),
         }
     }
 
     pub fn stop(&mut self) {
-        self.sink = Sink::new(&self.device);
+        self.pause_at(Duration::from_secs(0));
+    }
+
+    // Moves to the position, carrying on running if it was
+    pub fn set(&mut self, position: Duration) {
+        self.elapsed = position;
+        if self.started.is_some() {
+            self.started = Some(Instant::now());
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        match self.started {
+            Some(started) => self.elapsed + started.elapsed(),
+            None => self.elapsed,
+        }
     }
 }
 
@@ -179,24 +419,68 @@ pub struct UI<'a> {
     pub tabs: TabsState<'a>,
     pub lib_cols: LibraryCols,
     pub now_playing: Track,
-    pub rx: Receiver<bool>,
-    pub tx: Sender<Track>,
-    pub ptx: Sender<bool>,
+    pub player: Sender<PlayerCommand>,
+    pub events: Receiver<PlayerEvent>,
+    pub volume: f32,
+    pub muted: bool,
+    // How far the seek keys jump
+    pub seek_step: Duration,
     pub search_input: String,
     pub fuzzy_searcher: EngineGroup,
-    pub search_results: Vec<Media>,
+    pub search_results: Vec<Hit<Media>>,
     pub search_select: usize,
     pub stats: Stats,
+    pub plays: PlayLog,
+    pub ratings: Ratings,
+    // Saved playlists first, then the smart ones
+    pub playlists: ListState<Playlist>,
+    pub smart_playlists: Vec<SmartPlaylist>,
+    pub playlists_folder: String,
+    pub playlist_tracks: Vec<Track>,
+    // Entries of the selected playlist that aren't in the library
+    pub playlist_missing: usize,
+    pub queue_select: usize,
+    // Most recently played first
+    pub history: Vec<Track>,
+    pub history_select: usize,
+    pub clock: PlaybackClock,
+    pub prompt: Option<Prompt>,
+    pub status: Option<(String, Instant)>,
+    pub radio: Option<Radio>,
+    // How many tracks radio mode waits before playing one again
+    pub radio_window: usize,
+    // Queue changes that can be undone or redone, most recent last
+    pub undo_stack: Vec<(String, QueueSnapshot)>,
+    pub redo_stack: Vec<(String, QueueSnapshot)>,
+    pub stop_after_current: bool,
+    // Set after stopping so the queue doesn't start up again on its own
+    pub holding: bool,
+    pub queue_end: QueueEnd,
+    // Everything played since the queue last ran out, for repeating it
+    pub queue_run: Vec<Track>,
+    // What the audio thread was last told to play after the current
+    // track, and whether it will be coming off the front of the queue
+    pub next_sent: Option<Track>,
+    pub next_from_queue: bool,
+    // Numbers each play and stop sent to the audio thread, so anything
+    // it said about the tracks before the latest one can be ignored
+    pub generation: u64,
+    // Paths of tracks that turned out to be gone or broken, kept for this session only
+    pub missing: HashSet<String>,
 }
 
 impl<'a> UI<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         database: &[Artist],
-        rx: Receiver<bool>,
-        tx: Sender<Track>,
-        ptx: Sender<bool>,
+        player: Sender<PlayerCommand>,
+        events: Receiver<PlayerEvent>,
         fuzzy_searcher: EngineGroup,
         stats: Stats,
+        plays: PlayLog,
+        ratings: Ratings,
+        smart_playlists: &[SmartPlaylist],
+        playlists_folder: &str,
     ) -> UI<'a> {
         // Generate initial list states
         let art_col = ListState::new(database);
@@ -209,42 +493,123 @@ impl<'a> UI<'a> {
             albums: al_col,
             tracks: tr_col,
             current_active: 0,
+            input: None,
+            no_matches: false,
         };
 
-        UI {
+        let mut ui = UI {
             queue: SonikQueue::new(),
             should_quit: false,
-            tabs: TabsState::new(vec!["queue", "library", "search"]),
+            tabs: TabsState::new(vec!["queue", "library", "search", "playlists", "history"]),
             lib_cols,
             now_playing: Track::dummy(),
-            rx,
-            tx,
-            ptx,
+            player,
+            events,
+            volume: 1.,
+            muted: false,
+            seek_step: Duration::from_secs(10),
             search_input: String::new(),
             fuzzy_searcher,
             search_results: Vec::new(),
             search_select: 0,
             stats,
+            plays,
+            ratings,
+            playlists: ListState::new(&[]),
+            smart_playlists: smart_playlists.to_vec(),
+            playlists_folder: playlists_folder.to_string(),
+            playlist_tracks: Vec::new(),
+            playlist_missing: 0,
+            queue_select: 0,
+            history: Vec::new(),
+            history_select: 0,
+            clock: PlaybackClock::new(),
+            prompt: None,
+            status: None,
+            radio: None,
+            radio_window: 50,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            stop_after_current: false,
+            holding: false,
+            queue_end: QueueEnd::Stop,
+            queue_run: Vec::new(),
+            next_sent: None,
+            next_from_queue: false,
+            generation: 0,
+            missing: HashSet::new(),
+        };
+        ui.reload_playlists(0);
+
+        ui
+    }
+
+    fn play(&mut self, track: Track) {
+        self.generation += 1;
+        self.send(PlayerCommand::Play(track.clone(), self.generation));
+        self.on_now_playing(track);
+    }
+
+    // Every track that gets played goes through here, whether it was
+    // picked or ran on from the last one, so the play counts stay up to date
+    fn on_now_playing(&mut self, track: Track) {
+        self.plays.record(&track);
+        if let Some(radio) = &mut self.radio {
+            radio.remember(&track);
+        }
+
+        self.clock.start_at(Duration::from_secs(0));
+        self.holding = false;
+
+        if self.queue_run.last() != Some(&track) {
+            self.queue_run.push(track.clone());
+            if self.queue_run.len() > QUEUE_RUN_LIMIT {
+                self.queue_run.remove(0);
+            }
+        }
+
+        // Repeating a track shouldn't fill the history with copies of it
+        let previous = std::mem::replace(&mut self.now_playing, track);
+        if !previous.file_path.is_empty() && previous != self.now_playing {
+            self.history.insert(0, previous);
+            self.history.truncate(HISTORY_LIMIT);
+        }
+    }
+
+    // Goes back to the last played track, putting the current one back in the queue
+    pub fn play_previous(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        let previous = self.history.remove(0);
+        let current = std::mem::replace(&mut self.now_playing, Track::dummy());
+        if !current.file_path.is_empty() {
+            self.queue.add_to_front(current);
         }
+
+        self.play(previous);
+        self.clamp_history_select();
     }
 
     pub fn on_enter(&mut self) {
         match self.tabs.index {
+            0 => {
+                // Play the selected track right away
+                if let Some(track) = self.queue.take_at(self.queue_select) {
+                    self.play(track);
+                    self.clamp_queue_select();
+                }
+            }
             1 => {
                 if self.lib_cols.current_active == 2 {
                     let track = self.lib_cols.tracks.items[self.lib_cols.tracks.selected].clone();
-                    let audio_copy = track.clone();
-
-                    if let Ok(()) = self.tx.send(audio_copy) {}
-                    self.now_playing = track;
+                    self.play(track);
                 } else if self.lib_cols.current_active == 1 {
                     let mut album =
                         self.lib_cols.albums.items[self.lib_cols.albums.selected].clone();
                     let track = album.tracks.remove(0);
-                    let audio_copy = track.clone();
-
-                    if let Ok(()) = self.tx.send(audio_copy) {}
-                    self.now_playing = track;
+                    self.play(track);
 
                     while let Some(t) = album.tracks.pop() {
                         self.queue.add_to_front(t);
@@ -254,15 +619,12 @@ impl<'a> UI<'a> {
             2 => {
                 if self.search_input == "" && self.search_results.is_empty() {
                 } else if self.search_input == "" {
-                    match &self.search_results[self.search_select] {
+                    match &self.search_results[self.search_select].item {
                         Media::Artist(_a) => {}
                         Media::Album(a) => {
                             let mut album = a.clone();
                             let track = album.tracks.remove(0);
-                            let audio_copy = track.clone();
-
-                            if let Ok(()) = self.tx.send(audio_copy) {}
-                            self.now_playing = track;
+                            self.play(track);
 
                             while let Some(t) = album.tracks.pop() {
                                 self.queue.add_to_front(t);
@@ -270,147 +632,1384 @@ impl<'a> UI<'a> {
                         }
                         Media::Track(t) => {
                             let track = t.clone();
-                            let audio_copy = track.clone();
-                            if let Ok(()) = self.tx.send(audio_copy) {}
-                            self.now_playing = track;
+                            self.play(track);
                         }
                     }
                 } else {
                     self.search();
                 }
             }
+            3 => {
+                // Playlists are read again so they reflect the current library
+                self.refresh_playlist();
+                let mut tracklist = self.playlist_tracks.clone();
+                if tracklist.is_empty() {
+                    return;
+                }
+
+                let track = tracklist.remove(0);
+                self.play(track);
+
+                while let Some(t) = tracklist.pop() {
+                    self.queue.add_to_front(t);
+                }
+            }
+            4 => {
+                if let Some(track) = self.history.get(self.history_select) {
+                    let track = track.clone();
+                    self.play(track);
+                }
+            }
             _ => {}
         }
     }
 
     pub fn play_from_queue(&mut self) {
-        let track = self.queue.take();
-        let audio_copy = track.clone();
-        if let Ok(()) = self.tx.send(audio_copy) {}
-        self.now_playing = track;
+        self.feed_radio();
+        if let Some(track) = self.queue.take_at(0) {
+            self.play(track);
+            self.clamp_queue_select();
+        }
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        if let Ok(()) = self.player.send(command) {}
     }
 
     pub fn pause_play(&mut self) {
-        if let Ok(()) = self.ptx.send(true) {}
+        if self.now_playing.file_path.is_empty() {
+            return;
+        }
+
+        if self.clock.is_running() {
+            self.send(PlayerCommand::Pause);
+        } else {
+            self.send(PlayerCommand::Resume);
+        }
+        self.clock.pause_play();
     }
 
-    pub fn add_to_queue(&mut self) {
-        match self.tabs.index {
-            1 => {
-                if self.lib_cols.current_active == 2 {
-                    let track = self.lib_cols.tracks.items[self.lib_cols.tracks.selected].clone();
-                    self.queue.add(track);
-                } else if self.lib_cols.current_active == 1 {
-                    for t in &self.lib_cols.albums.items[self.lib_cols.albums.selected].tracks {
-                        self.queue.add(t.clone());
-                    }
-                } else {
-                    for a in &self.lib_cols.artists.items[self.lib_cols.artists.selected].albums {
-                        for t in &a.tracks {
-                            self.queue.add(t.clone());
-                        }
-                    }
+    pub fn on_player_event(&mut self, event: PlayerEvent) {
+        match event {
+            // A track that ran on or finished just as another was picked is old news
+            PlayerEvent::Started(track, generation) => {
+                if generation == self.generation {
+                    self.on_track_started(track);
                 }
             }
-            2 => match &self.search_results[self.search_select] {
-                Media::Artist(a) => {
-                    for album in &a.albums {
-                        for t in &album.tracks {
-                            self.queue.add(t.clone());
-                        }
-                    }
-                }
-                Media::Album(a) => {
-                    for t in &a.tracks {
-                        self.queue.add(t.clone());
-                    }
+            PlayerEvent::Position(position) => self.on_position(position),
+            PlayerEvent::Finished(track, generation) => {
+                if generation == self.generation && track == self.now_playing {
+                    self.on_track_end();
                 }
-                Media::Track(t) => {
-                    let track = t.clone();
-                    self.queue.add(track);
-                }
-            },
-            _ => {}
+            }
+            PlayerEvent::Unplayable(track, reason) => self.on_unplayable(track, reason),
         }
     }
 
-    pub fn add_to_front(&mut self) {
-        if let 1 = self.tabs.index {
-            if self.lib_cols.current_active == 2 {
-                let track = self.lib_cols.tracks.items[self.lib_cols.tracks.selected].clone();
-                self.queue.add_to_front(track);
-            } else if self.lib_cols.current_active == 1 {
-                let mut tracklist = self.lib_cols.albums.items[self.lib_cols.albums.selected]
-                    .tracks
-                    .clone();
-                while let Some(t) = tracklist.pop() {
-                    self.queue.add_to_front(t);
-                }
+    // Marks a track that couldn't be played as missing and skips it, taking
+    // every copy of it out of the queue so repeating doesn't run into it again
+    fn on_unplayable(&mut self, track: Track, reason: String) {
+        self.set_status(format!("skipped {}: {}", track.title, reason));
+        self.missing.insert(track.file_path.clone());
+
+        let missing = &self.missing;
+        self.queue.retain(|t| !missing.contains(&t.file_path));
+        self.queue_run.retain(|t| !missing.contains(&t.file_path));
+        self.clamp_queue_select();
+
+        // The audio thread stopped, taking whatever was lined up with it
+        self.next_sent = None;
+
+        if track == self.now_playing {
+            // A track brought back paused from the last session stays
+            // paused, so the one that takes its place should be too
+            let paused = !self.clock.is_running();
+            self.clock.stop();
+            self.feed_radio();
+            if self.queue.is_empty() {
+                self.blank_now_play();
             } else {
-                for a in &self.lib_cols.artists.items[self.lib_cols.artists.selected].albums {
-                    let mut tracklist = a.tracks.clone();
-                    while let Some(t) = tracklist.pop() {
-                        self.queue.add_to_front(t);
-                    }
+                self.play_from_queue();
+                if paused {
+                    self.pause_play();
                 }
             }
         }
     }
 
-    pub fn clear_queue(&mut self) {
-        if let Ok(()) = self.ptx.send(false) {}
-        self.queue.clear();
-        self.blank_now_play();
+    // Playback ran straight on into the track that was lined up
+    fn on_track_started(&mut self, track: Track) {
+        if self.next_from_queue && self.queue.tracks.front() == Some(&track) {
+            self.queue.take_at(0);
+            self.clamp_queue_select();
+        }
+
+        self.next_sent = None;
+        self.on_now_playing(track);
     }
 
-    pub fn blank_now_play(&mut self) {
-        match self.now_playing.title.as_ref() {
-            "" => {}
+    // The track that should follow the current one without a gap, if any,
+    // and whether it comes off the front of the queue. Running out of queue
+    // is left to the end of the track, as that may do all sorts of things.
+    fn upcoming(&mut self) -> (Option<Track>, bool) {
+        if self.now_playing.file_path.is_empty() || self.stop_after_current {
+            return (None, false);
+        }
+
+        match self.queue.repeat {
+            RepeatMode::One => (Some(self.now_playing.clone()), false),
+            RepeatMode::All if self.queue.is_empty() => (Some(self.now_playing.clone()), false),
             _ => {
-                self.now_playing = Track::dummy();
+                self.feed_radio();
+                (self.queue.tracks.front().cloned(), true)
             }
         }
     }
 
-    pub fn search(&mut self) {
-        if self.search_input == "" {
-            return;
+    // Lets the audio thread know what to play next whenever that changes,
+    // so it can have it ready before the current track ends
+    pub fn sync_next(&mut self) {
+        let (next, from_queue) = self.upcoming();
+        if next != self.next_sent {
+            self.send(PlayerCommand::SetNext(next.clone()));
+            self.next_sent = next;
+        }
+        self.next_from_queue = from_queue;
+    }
+
+    // The queue starts playing on its own when something
+    // is added to it while nothing is playing
+    pub fn play_if_idle(&mut self) {
+        if self.now_playing.file_path.is_empty() && !self.holding && !self.queue.is_empty() {
+            self.play_from_queue();
         }
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.queue.repeat = self.queue.repeat.next();
+    }
+
+    pub fn shuffle_queue(&mut self) {
+        let before = self.queue.snapshot();
+        let plays = &self.plays;
+        let ratings = &self.ratings;
+
+        // Unrated tracks count as middling, and every play makes a track less likely
+        self.queue.shuffle(|track| {
+            let stars = match ratings.rating(track) {
+                0 => 3,
+                r => r,
+            };
+            f64::from(stars) / f64::from(1 + plays.count(track))
+        });
+        self.push_undo("shuffling the queue", before);
+    }
 
-        let query_term = SearchQuery::new(self.search_input.as_str());
-        self.search_input = String::new();
+    pub fn cycle_shuffle_mode(&mut self) {
+        self.queue.shuffle_mode = self.queue.shuffle_mode.next();
+        let message = format!("shuffle mode: {}", self.queue.shuffle_mode.label());
+        self.set_status(message);
+    }
+
+    pub fn unshuffle_queue(&mut self) {
+        let before = self.queue.snapshot();
+        if self.queue.unshuffle() {
+            self.push_undo("unshuffling the queue", before);
+        } else {
+            self.set_status("the queue hasn't been shuffled".to_string());
+        }
+    }
 
-        if query_term.is_none() {
+    // Remembers how the queue was before a change, unless nothing changed
+    fn push_undo(&mut self, description: &str, before: QueueSnapshot) {
+        if before.tracks == self.queue.tracks {
             return;
         }
 
-        self.search_results = match db_search(&self.fuzzy_searcher, query_term.unwrap()) {
-            SearchResult::Artists(r) => r
-                .iter()
-                .map(|x| Media::Artist(self.lib_cols.artists.items[*x].clone()))
-                .collect(),
-            SearchResult::Albums(r) => r
-                .iter()
-                .map(|x| Media::Album(self.lib_cols.artists.items[x.0].albums[x.1].clone()))
-                .collect(),
-            SearchResult::Tracks(r) => r
-                .iter()
-                .map(|x| {
-                    Media::Track(self.lib_cols.artists.items[x.0].albums[x.1].tracks[x.2].clone())
-                })
-                .collect(),
-        };
+        self.undo_stack.push((description.to_string(), before));
+        self.redo_stack.clear();
+
+        let mut kept_tracks: usize = self.undo_stack.iter().map(|(_, s)| s.tracks.len()).sum();
+        while self.undo_stack.len() > UNDO_LIMIT
+            || (kept_tracks > UNDO_TRACK_LIMIT && self.undo_stack.len() > 1)
+        {
+            let (_, dropped) = self.undo_stack.remove(0);
+            kept_tracks -= dropped.tracks.len();
+        }
     }
 
-    pub fn on_up_search(&mut self) {
-        if self.search_select > 0 {
-            self.search_select -= 1;
+    pub fn undo(&mut self) {
+        match self.undo_stack.pop() {
+            Some((description, snapshot)) => {
+                self.redo_stack
+                    .push((description.clone(), self.queue.snapshot()));
+                self.queue.restore(snapshot);
+                self.clamp_queue_select();
+                self.set_status(format!("undid {}", description));
+            }
+            None => self.set_status("nothing to undo".to_string()),
+        }
+    }
+
+    pub fn redo(&mut self) {
+        match self.redo_stack.pop() {
+            Some((description, snapshot)) => {
+                self.undo_stack
+                    .push((description.clone(), self.queue.snapshot()));
+                self.queue.restore(snapshot);
+                self.clamp_queue_select();
+                self.set_status(format!("redid {}", description));
+            }
+            None => self.set_status("nothing to redo".to_string()),
+        }
+    }
+
+    // Turns radio mode off, or asks what it should play
+    pub fn toggle_radio(&mut self) {
+        if self.radio.take().is_some() {
+            self.set_status("radio off".to_string());
         } else {
-            self.search_select = self.search_results.len() - 1;
+            self.start_prompt(PromptKind::Radio);
         }
     }
 
-    pub fn on_down_search(&mut self) {
-        self.search_select = (self.search_select + 1) % self.search_results.len();
+    pub fn start_radio(&mut self, query: &str) {
+        let radio = match Radio::new(query, self.radio_window) {
+            Ok(r) => r,
+            Err(()) => {
+                self.set_status(format!("\"{}\" is not a valid query", query.trim()));
+                return;
+            }
+        };
+        self.radio = Some(radio);
+
+        self.feed_radio();
+        if self.queue.is_empty() {
+            self.radio = None;
+            self.set_status(format!("nothing in the library matches {}", query.trim()));
+            return;
+        }
+
+        if self.now_playing.file_path.is_empty() {
+            self.play_from_queue();
+        }
+        self.set_status("radio on".to_string());
+    }
+
+    // Tops the queue up with random tracks when radio mode is on
+    fn feed_radio(&mut self) {
+        let count = RADIO_QUEUE_LENGTH.saturating_sub(self.queue.len());
+        if count == 0 {
+            return;
+        }
+
+        if let Some(radio) = &mut self.radio {
+            for track in radio.pick(self.lib_cols.artists.all(), &self.plays, count) {
+                if !self.missing.contains(&track.file_path) {
+                    self.queue.add(track);
+                }
+            }
+        }
+    }
+
+    pub fn rate_now_playing(&mut self) {
+        if self.now_playing.file_path.is_empty() {
+            return;
+        }
+
+        let message = match self.ratings.cycle(&self.now_playing) {
+            0 => format!("cleared the rating of {}", self.now_playing.title),
+            r => format!("rated {} {}/{}", self.now_playing.title, r, MAX_RATING),
+        };
+        self.set_status(message);
+    }
+
+    // Called when the audio thread reports that the current track has finished
+    fn on_track_end(&mut self) {
+        if self.stop_after_current {
+            self.stop_after_current = false;
+            self.holding = true;
+            self.clock.stop();
+            self.blank_now_play();
+            self.set_status("stopped, press > to go on with the queue".to_string());
+            return;
+        }
+
+        match self.queue.repeat {
+            RepeatMode::One => {
+                let track = self.now_playing.clone();
+                self.play(track);
+            }
+            // A lone track played straight from the library is still repeated
+            RepeatMode::All if self.queue.is_empty() => {
+                let track = self.now_playing.clone();
+                self.play(track);
+            }
+            _ => {
+                self.feed_radio();
+                if self.queue.is_empty() {
+                    self.on_queue_end();
+                } else {
+                    self.play_from_queue();
+                }
+            }
+        }
+    }
+
+    fn on_queue_end(&mut self) {
+        let run = std::mem::take(&mut self.queue_run);
+
+        match self.queue_end {
+            QueueEnd::Stop => {}
+            QueueEnd::Repeat => {
+                for track in run {
+                    self.queue.add(track);
+                }
+            }
+            // Radio mode starts playing on its own once nothing is
+            QueueEnd::Random => {
+                self.blank_now_play();
+                self.start_radio("");
+                return;
+            }
+            QueueEnd::NextAlbum => {
+                if let Some(album) = self.next_album() {
+                    for track in album.tracks {
+                        self.queue.add(track);
+                    }
+                }
+            }
+            QueueEnd::Quit => self.should_quit = true,
+        }
+
+        if self.queue.is_empty() {
+            self.blank_now_play();
+        } else {
+            self.play_from_queue();
+        }
+    }
+
+    // The album after the one playing, going by year, from the same artist
+    fn next_album(&self) -> Option<Album> {
+        let playing = &self.now_playing;
+        let artist = self.lib_cols.artists.all().iter().find(|a| {
+            a.albums
+                .iter()
+                .any(|album| album.tracks.iter().any(|t| t == playing))
+        })?;
+
+        let mut albums = artist.albums.iter().collect::<Vec<&Album>>();
+        if albums.len() < 2 {
+            return None;
+        }
+        albums.sort_by_key(|a| (a.year, a.title.to_lowercase()));
+
+        let idx = albums
+            .iter()
+            .position(|a| a.tracks.iter().any(|t| t == playing))?;
+
+        // After the last album comes the first one again
+        Some(albums[(idx + 1) % albums.len()].clone())
+    }
+
+    pub fn seek_forward(&mut self) {
+        let position = self.clock.elapsed() + self.seek_step;
+        self.seek_to(position);
+    }
+
+    pub fn seek_backward(&mut self) {
+        let position = self.clock.elapsed().checked_sub(self.seek_step);
+        self.seek_to(position.unwrap_or_default());
+    }
+
+    pub fn seek_to(&mut self, position: Duration) {
+        if self.now_playing.file_path.is_empty() {
+            return;
+        }
+
+        let duration = Duration::from_millis(u64::from(self.now_playing.duration));
+        let position = if duration > Duration::from_secs(0) && position > duration {
+            duration
+        } else {
+            position
+        };
+
+        self.send(PlayerCommand::Seek(position));
+    }
+
+    // The audio thread reports the position every so often and after
+    // seeking, which keeps the clock from drifting away from the audio
+    fn on_position(&mut self, position: Duration) {
+        if !self.now_playing.file_path.is_empty() {
+            self.clock.set(position);
+        }
+    }
+
+    pub fn volume_up(&mut self) {
+        self.set_volume(self.volume + VOLUME_STEP);
+    }
+
+    pub fn volume_down(&mut self) {
+        self.set_volume(self.volume - VOLUME_STEP);
+    }
+
+    // Changing the volume while muted unmutes
+    fn set_volume(&mut self, volume: f32) {
+        // Rounded so repeated steps don't drift off the percentages
+        self.volume = (volume.clamp(0., 1.) * 100.).round() / 100.;
+        self.muted = false;
+        self.send(PlayerCommand::SetVolume(self.volume));
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        let volume = if self.muted { 0. } else { self.volume };
+        self.send(PlayerCommand::SetVolume(volume));
+    }
+
+    pub fn toggle_stop_after_current(&mut self) {
+        self.stop_after_current = !self.stop_after_current;
+        if self.stop_after_current {
+            self.set_status("stopping after this track".to_string());
+        } else {
+            self.set_status("not stopping after this track".to_string());
+        }
+    }
+
+    pub fn cycle_queue_end(&mut self) {
+        self.queue_end = self.queue_end.next();
+        let message = format!("when the queue ends: {}", self.queue_end.label());
+        self.set_status(message);
+    }
+
+    // The tracks under the selection in the library or search tab
+    fn selected_tracks(&self) -> Vec<Track> {
+        let artist_tracks = |a: &Artist| {
+            a.albums
+                .iter()
+                .flat_map(|album| album.tracks.iter().cloned())
+                .collect::<Vec<Track>>()
+        };
+
+        match self.tabs.index {
+            1 => match self.lib_cols.current_active {
+                2 => vec![self.lib_cols.tracks.items[self.lib_cols.tracks.selected].clone()],
+                1 => self.lib_cols.albums.items[self.lib_cols.albums.selected]
+                    .tracks
+                    .clone(),
+                _ => artist_tracks(&self.lib_cols.artists.items[self.lib_cols.artists.selected]),
+            },
+            2 => match self.search_results.get(self.search_select).map(|h| &h.item) {
+                Some(Media::Artist(a)) => artist_tracks(a),
+                Some(Media::Album(a)) => a.tracks.clone(),
+                Some(Media::Track(t)) => vec![t.clone()],
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn add_to_queue(&mut self) {
+        let before = self.queue.snapshot();
+        self.add_selection_to_queue();
+        self.push_undo("adding to the queue", before);
+    }
+
+    fn add_selection_to_queue(&mut self) {
+        match self.tabs.index {
+            1 | 2 => {
+                for t in self.selected_tracks() {
+                    self.queue.add(t);
+                }
+            }
+            3 => {
+                self.refresh_playlist();
+                for t in &self.playlist_tracks {
+                    self.queue.add(t.clone());
+                }
+            }
+            4 => {
+                if let Some(track) = self.history.get(self.history_select) {
+                    self.queue.add(track.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn add_to_front(&mut self) {
+        let before = self.queue.snapshot();
+        self.add_selection_to_front();
+        self.push_undo("adding to the front of the queue", before);
+    }
+
+    fn add_selection_to_front(&mut self) {
+        if let 1 = self.tabs.index {
+            if self.lib_cols.current_active == 2 {
+                let track = self.lib_cols.tracks.items[self.lib_cols.tracks.selected].clone();
+                self.queue.add_to_front(track);
+            } else if self.lib_cols.current_active == 1 {
+                let mut tracklist = self.lib_cols.albums.items[self.lib_cols.albums.selected]
+                    .tracks
+                    .clone();
+                while let Some(t) = tracklist.pop() {
+                    self.queue.add_to_front(t);
+                }
+            } else {
+                for a in &self.lib_cols.artists.items[self.lib_cols.artists.selected].albums {
+                    let mut tracklist = a.tracks.clone();
+                    while let Some(t) = tracklist.pop() {
+                        self.queue.add_to_front(t);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn remove_from_queue(&mut self) {
+        let before = self.queue.snapshot();
+        if let Some(track) = self.queue.remove(self.queue_select) {
+            self.push_undo(&format!("removing {}", track.title), before);
+        }
+        self.clamp_queue_select();
+    }
+
+    // The selection follows the track as it moves
+    pub fn move_up_in_queue(&mut self) {
+        let before = self.queue.snapshot();
+        if self.queue.move_up(self.queue_select) {
+            self.queue_select -= 1;
+            self.push_undo("moving a track up", before);
+        }
+    }
+
+    pub fn move_down_in_queue(&mut self) {
+        let before = self.queue.snapshot();
+        if self.queue.move_down(self.queue_select) {
+            self.queue_select += 1;
+            self.push_undo("moving a track down", before);
+        }
+    }
+
+    pub fn move_to_top_of_queue(&mut self) {
+        let before = self.queue.snapshot();
+        if self.queue.move_to_front(self.queue_select) {
+            self.queue_select = 0;
+            self.push_undo("moving a track to the top", before);
+        }
+    }
+
+    pub fn on_up_queue(&mut self) {
+        if self.queue_select > 0 {
+            self.queue_select -= 1;
+        } else {
+            self.queue_select = self.queue.len().saturating_sub(1);
+        }
+    }
+
+    pub fn on_down_queue(&mut self) {
+        if !self.queue.is_empty() {
+            self.queue_select = (self.queue_select + 1) % self.queue.len();
+        }
+    }
+
+    // Keeps the selection inside the queue as it shrinks
+    fn clamp_queue_select(&mut self) {
+        if self.queue_select >= self.queue.len() {
+            self.queue_select = self.queue.len().saturating_sub(1);
+        }
+    }
+
+    fn clamp_history_select(&mut self) {
+        if self.history_select >= self.history.len() {
+            self.history_select = self.history.len().saturating_sub(1);
+        }
+    }
+
+    pub fn clear_queue(&mut self) {
+        let before = self.queue.snapshot();
+        self.generation += 1;
+        self.send(PlayerCommand::Stop(self.generation));
+        self.next_sent = None;
+        self.queue.clear();
+        self.queue_run.clear();
+        self.push_undo("clearing the queue", before);
+        self.queue_select = 0;
+        self.clock.stop();
+        self.blank_now_play();
+    }
+
+    pub fn blank_now_play(&mut self) {
+        match self.now_playing.title.as_ref() {
+            "" => {}
+            _ => {
+                self.now_playing = Track::dummy();
+            }
+        }
+    }
+
+    pub fn search(&mut self) {
+        if self.search_input == "" {
+            return;
+        }
+
+        let input = std::mem::take(&mut self.search_input);
+        let query = match SearchQuery::new(&input) {
+            Some(q) => q,
+            None => return,
+        };
+
+        // The search tab only looks up one name, the rest of
+        // the terms are only for saved queries
+        let ignored = query.terms.len() > 1;
+        let results = match db_search(&self.fuzzy_searcher, query) {
+            Some(r) => r,
+            None => {
+                self.set_status(
+                    "search needs a title:, album: or artist: term, \
+                     the others only work in smart playlists"
+                        .to_string(),
+                );
+                return;
+            }
+        };
+        if ignored {
+            self.set_status(
+                "only the first title:, album: or artist: term was searched, \
+                 the others only work in smart playlists"
+                    .to_string(),
+            );
+        }
+
+        self.search_results = match results {
+            SearchResult::Artists(r) => r
+                .into_iter()
+                .map(|h| h.map(|x| Media::Artist(self.lib_cols.artists.all()[x].clone())))
+                .collect(),
+            SearchResult::Albums(r) => r
+                .into_iter()
+                .map(|h| {
+                    h.map(|x| Media::Album(self.lib_cols.artists.all()[x.0].albums[x.1].clone()))
+                })
+                .collect(),
+            SearchResult::Tracks(r) => r
+                .into_iter()
+                .map(|h| {
+                    h.map(|x| {
+                        Media::Track(
+                            self.lib_cols.artists.all()[x.0].albums[x.1].tracks[x.2].clone(),
+                        )
+                    })
+                })
+                .collect(),
+        };
+        self.search_select = 0;
+    }
+
+    pub fn on_up_search(&mut self) {
+        if self.search_select > 0 {
+            self.search_select -= 1;
+        } else {
+            self.search_select = self.search_results.len() - 1;
+        }
+    }
+
+    pub fn on_down_search(&mut self) {
+        self.search_select = (self.search_select + 1) % self.search_results.len();
+    }
+
+    pub fn refresh_playlist(&mut self) {
+        let (tracks, missing) = match self.playlists.items.get(self.playlists.selected) {
+            Some(Playlist::Saved(p)) => match read_playlist(&p.path) {
+                Ok(entries) => resolve_playlist(&entries, self.lib_cols.artists.all()),
+                Err(()) => (Vec::new(), 0),
+            },
+            Some(Playlist::Smart(p)) => (p.evaluate(self.lib_cols.artists.all(), &self.plays), 0),
+            None => (Vec::new(), 0),
+        };
+        self.playlist_tracks = tracks;
+        self.playlist_missing = missing;
+    }
+
+    // Picks up playlists saved, renamed or removed since the list was last
+    // read, keeping the selection around the same place
+    pub fn reload_playlists(&mut self, select: usize) {
+        let mut items = list_playlists(&self.playlists_folder)
+            .into_iter()
+            .map(Playlist::Saved)
+            .collect::<Vec<Playlist>>();
+        items.extend(self.smart_playlists.iter().cloned().map(Playlist::Smart));
+
+        self.playlists = ListState::new(&items);
+        self.playlists.selected = select.min(items.len().saturating_sub(1));
+        self.refresh_playlist();
+    }
+
+    pub fn saved_playlists(&self) -> Vec<SavedPlaylist> {
+        self.playlists
+            .items
+            .iter()
+            .filter_map(|p| match p {
+                Playlist::Saved(s) => Some(s.clone()),
+                Playlist::Smart(_) => None,
+            })
+            .collect()
+    }
+
+    fn selected_saved_playlist(&mut self) -> Option<SavedPlaylist> {
+        match self.playlists.items.get(self.playlists.selected) {
+            Some(Playlist::Saved(p)) => Some(p.clone()),
+            Some(Playlist::Smart(p)) => {
+                let message = format!(
+                    "{} is a smart playlist, edit it in smart_playlists.toml",
+                    p.name
+                );
+                self.set_status(message);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn on_up_playlists(&mut self) {
+        if !self.playlists.items.is_empty() {
+            self.playlists.select_previous();
+            self.refresh_playlist();
+        }
+    }
+
+    pub fn on_down_playlists(&mut self) {
+        if !self.playlists.items.is_empty() {
+            self.playlists.select_next();
+            self.refresh_playlist();
+        }
+    }
+
+    pub fn new_playlist(&mut self, name: &str) {
+        let path = match playlist_path(&self.playlists_folder, name) {
+            Some(p) => p,
+            None => {
+                self.set_status(format!("\"{}\" is not a usable playlist name", name));
+                return;
+            }
+        };
+        if path.exists() {
+            self.set_status(format!(
+                "there is already a playlist called {}",
+                name.trim()
+            ));
+            return;
+        }
+
+        let created = fs::create_dir_all(&self.playlists_folder).is_ok()
+            && write_playlist(&path, &[]).is_ok();
+        if !created {
+            self.set_status(format!("could not create playlist {}", name.trim()));
+            return;
+        }
+
+        // New playlists go at the end of the saved ones
+        let mut saved = self.saved_playlists();
+        saved.push(SavedPlaylist {
+            name: name.trim().to_string(),
+            path,
+        });
+        save_playlist_order(&self.playlists_folder, &saved).ok();
+        self.reload_playlists(saved.len() - 1);
+    }
+
+    // Starts off the rename prompt with the current name to edit
+    pub fn start_rename_playlist(&mut self) {
+        if let Some(playlist) = self.selected_saved_playlist() {
+            self.start_prompt(PromptKind::RenamePlaylist);
+            if let Some(prompt) = &mut self.prompt {
+                prompt.text = playlist.name;
+            }
+        }
+    }
+
+    pub fn rename_playlist(&mut self, name: &str) {
+        let playlist = match self.selected_saved_playlist() {
+            Some(p) => p,
+            None => return,
+        };
+        let path = match playlist_path(&self.playlists_folder, name) {
+            Some(p) => p,
+            None => {
+                self.set_status(format!("\"{}\" is not a usable playlist name", name));
+                return;
+            }
+        };
+        if path == playlist.path {
+            return;
+        }
+        if path.exists() {
+            self.set_status(format!(
+                "there is already a playlist called {}",
+                name.trim()
+            ));
+            return;
+        }
+
+        if fs::rename(&playlist.path, &path).is_err() {
+            self.set_status(format!("could not rename {}", playlist.name));
+            return;
+        }
+
+        let mut saved = self.saved_playlists();
+        saved[self.playlists.selected] = SavedPlaylist {
+            name: name.trim().to_string(),
+            path,
+        };
+        save_playlist_order(&self.playlists_folder, &saved).ok();
+        self.reload_playlists(self.playlists.selected);
+    }
+
+    pub fn start_delete_playlist(&mut self) {
+        if self.selected_saved_playlist().is_some() {
+            self.start_prompt(PromptKind::DeletePlaylist);
+        }
+    }
+
+    pub fn delete_playlist(&mut self) {
+        let playlist = match self.selected_saved_playlist() {
+            Some(p) => p,
+            None => return,
+        };
+
+        if fs::remove_file(&playlist.path).is_err() {
+            self.set_status(format!("could not delete {}", playlist.name));
+            return;
+        }
+
+        let mut saved = self.saved_playlists();
+        saved.remove(self.playlists.selected);
+        save_playlist_order(&self.playlists_folder, &saved).ok();
+        self.reload_playlists(self.playlists.selected);
+        self.set_status(format!("deleted {}", playlist.name));
+    }
+
+    // Saved playlists can be moved around among themselves, smart
+    // playlists keep the order they have in their file
+    pub fn move_playlist_up(&mut self) {
+        let selected = self.playlists.selected;
+        if self.selected_saved_playlist().is_none() || selected == 0 {
+            return;
+        }
+
+        let mut saved = self.saved_playlists();
+        saved.swap(selected, selected - 1);
+        save_playlist_order(&self.playlists_folder, &saved).ok();
+        self.reload_playlists(selected - 1);
+    }
+
+    pub fn move_playlist_down(&mut self) {
+        let selected = self.playlists.selected;
+        let mut saved = self.saved_playlists();
+        if self.selected_saved_playlist().is_none() || selected + 1 >= saved.len() {
+            return;
+        }
+
+        saved.swap(selected, selected + 1);
+        save_playlist_order(&self.playlists_folder, &saved).ok();
+        self.reload_playlists(selected + 1);
+    }
+
+    // Adds the selected library or search item to the end of
+    // the saved playlist selected in the playlists tab
+    pub fn add_to_playlist(&mut self) {
+        let tracks = self.selected_tracks();
+        if tracks.is_empty() {
+            return;
+        }
+
+        let playlist = match self.playlists.items.get(self.playlists.selected) {
+            Some(Playlist::Saved(p)) => p.clone(),
+            _ => {
+                self.set_status("select a saved playlist in the playlists tab first".to_string());
+                return;
+            }
+        };
+
+        if append_to_playlist(&playlist.path, &tracks).is_ok() {
+            self.set_status(format!(
+                "added {} tracks to {}",
+                tracks.len(),
+                playlist.name
+            ));
+            self.refresh_playlist();
+        } else {
+            self.set_status(format!("could not add to {}", playlist.name));
+        }
+    }
+
+    pub fn on_up_history(&mut self) {
+        if self.history_select > 0 {
+            self.history_select -= 1;
+        } else {
+            self.history_select = self.history.len().saturating_sub(1);
+        }
+    }
+
+    pub fn on_down_history(&mut self) {
+        if !self.history.is_empty() {
+            self.history_select = (self.history_select + 1) % self.history.len();
+        }
+    }
+
+    // Snapshot of the queue and playback position, to be restored on the next launch
+    pub fn session(&self) -> Session {
+        let now_playing = if self.now_playing.file_path.is_empty() {
+            None
+        } else {
+            Some(self.now_playing.clone())
+        };
+
+        Session {
+            queue: self.queue.tracks.iter().cloned().collect(),
+            now_playing,
+            position: self.clock.elapsed().as_millis() as u32,
+        }
+    }
+
+    // Puts the last session's queue back, returning the track to resume
+    // and where to resume it from if playback should pick up again
+    pub fn restore(&mut self, session: Session, resume: bool) -> Option<(Track, Duration)> {
+        for track in session.queue {
+            self.queue.add(track);
+        }
+
+        let track = session.now_playing?;
+        if resume {
+            let position = Duration::from_millis(u64::from(session.position));
+            self.now_playing = track.clone();
+            self.clock.pause_at(position);
+            Some((track, position))
+        } else {
+            // Nothing is lost, it just plays next instead
+            self.queue.add_to_front(track);
+            None
+        }
+    }
+
+    pub fn save(&self, config: &Config) -> Result<(), ()> {
+        self.plays.save(&config.plays_path())?;
+        self.ratings.save(&config.ratings_path())?;
+        self.session().save(&config.session_path())
+    }
+
+    // Shows a message in the status box for a little while
+    pub fn set_status(&mut self, message: String) {
+        self.status = Some((message, Instant::now()));
+    }
+
+    pub fn current_status(&self) -> Option<&str> {
+        match &self.status {
+            Some((message, shown)) if shown.elapsed() < STATUS_DURATION => Some(message),
+            _ => None,
+        }
+    }
+
+    pub fn start_prompt(&mut self, kind: PromptKind) {
+        self.prompt = Some(Prompt {
+            kind,
+            text: String::new(),
+        });
+    }
+
+    pub fn push_prompt(&mut self, c: char) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.text.push(c);
+        }
+    }
+
+    pub fn pop_prompt(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.text.pop();
+        }
+    }
+
+    // Drops whatever was typed without acting on it
+    pub fn cancel_prompt(&mut self) {
+        self.prompt = None;
+    }
+
+    // Acts on whatever was typed; an empty prompt does nothing
+    pub fn finish_prompt(&mut self) {
+        let prompt = match self.prompt.take() {
+            Some(p) => p,
+            None => return,
+        };
+        if prompt.text.trim().is_empty() && !matches!(prompt.kind, PromptKind::Radio) {
+            return;
+        }
+
+        match prompt.kind {
+            PromptKind::SavePlaylist => self.save_playlist(&prompt.text),
+            PromptKind::NewPlaylist => self.new_playlist(&prompt.text),
+            PromptKind::RenamePlaylist => self.rename_playlist(&prompt.text),
+            PromptKind::DeletePlaylist => {
+                if prompt.text.trim().eq_ignore_ascii_case("y") {
+                    self.delete_playlist();
+                }
+            }
+            PromptKind::Radio => self.start_radio(&prompt.text),
+            PromptKind::Seek => {
+                let duration = Duration::from_millis(u64::from(self.now_playing.duration));
+                match parse_position(&prompt.text, duration) {
+                    Some(position) => self.seek_to(position),
+                    None => self.set_status(format!("can't seek to {}", prompt.text.trim())),
+                }
+            }
+        }
+    }
+
+    pub fn save_playlist(&mut self, name: &str) {
+        let folder = self.playlists_folder.clone();
+        let path = match playlist_path(&folder, name) {
+            Some(p) => p,
+            None => {
+                self.set_status(format!("\"{}\" is not a usable playlist name", name));
+                return;
+            }
+        };
+
+        let tracks = self.queue.tracks.iter().cloned().collect::<Vec<Track>>();
+        let saved = fs::create_dir_all(&folder).is_ok() && write_playlist(&path, &tracks).is_ok();
+
+        if saved {
+            self.reload_playlists(self.playlists.selected);
+            self.set_status(format!("saved {} tracks to {}", tracks.len(), name.trim()));
+        } else {
+            self.set_status(format!("could not save playlist {}", name.trim()));
+        }
+    }
+
+    // Adds the tracks of an M3U, M3U8 or PLS file to the end of the queue
+    pub fn load_playlist(&mut self, path: &Path) {
+        let entries = match read_playlist(path) {
+            Ok(e) => e,
+            Err(()) => {
+                self.set_status(format!("could not read {}", path.to_string_lossy()));
+                return;
+            }
+        };
+
+        let (tracks, missing) = resolve_playlist(&entries, self.lib_cols.artists.all());
+        let found = tracks.len();
+        let before = self.queue.snapshot();
+        for track in tracks {
+            self.queue.add(track);
+        }
+        self.push_undo("loading a playlist", before);
+
+        if missing > 0 {
+            self.set_status(format!(
+                "queued {} tracks, {} not in library",
+                found, missing
+            ));
+        } else {
+            self.set_status(format!("queued {} tracks", found));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::database::create_fuzzy_searcher;
+    use crossbeam_channel::unbounded;
+
+    fn track(album: &str, num: u32) -> Track {
+        Track {
+            file_path: format!("/music/{}/{}.mp3", album, num),
+            title: format!("{} {}", album, num),
+            artist: "artist".to_string(),
+            album_artist: "artist".to_string(),
+            album: album.to_string(),
+            track_num: num,
+            duration: 60_000,
+            ..Track::dummy()
+        }
+    }
+
+    fn album(title: &str, year: i32, count: u32) -> Album {
+        Album {
+            title: title.to_string(),
+            artist: "artist".to_string(),
+            year,
+            tracks: (1..=count).map(|n| track(title, n)).collect(),
+            gain: None,
+        }
+    }
+
+    // A UI over one artist with two albums, and the commands it sends
+    fn ui<'a>() -> (UI<'a>, Receiver<PlayerCommand>) {
+        let library = vec![Artist {
+            title: "artist".to_string(),
+            albums: vec![album("first", 2000, 3), album("second", 2001, 2)],
+        }];
+        let stats = Stats {
+            artists: 1,
+            albums: 2,
+            tracks: 5,
+            total_time: 300_000,
+        };
+        let (command_tx, command_rx) = unbounded();
+        let (_, event_rx) = unbounded();
+
+        let ui = UI::new(
+            &library,
+            command_tx,
+            event_rx,
+            create_fuzzy_searcher(&library).unwrap(),
+            stats,
+            PlayLog::default(),
+            Ratings::default(),
+            &[],
+            "/nonexistent/playlists",
+        );
+        (ui, command_rx)
+    }
+
+    // The titles of the tracks the audio thread was told to play
+    fn played(commands: &Receiver<PlayerCommand>) -> Vec<String> {
+        commands
+            .try_iter()
+            .filter_map(|c| match c {
+                PlayerCommand::Play(t, _) => Some(t.title),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn titles(ui: &UI) -> Vec<String> {
+        ui.queue.tracks.iter().map(|t| t.title.clone()).collect()
+    }
+
+    #[test]
+    fn started_takes_the_lined_up_track_off_the_queue() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+        ui.queue.add(track("first", 3));
+        ui.sync_next();
+        assert_eq!(ui.next_sent, Some(track("first", 2)));
+
+        ui.on_player_event(PlayerEvent::Started(track("first", 2), ui.generation));
+        assert_eq!(ui.now_playing, track("first", 2));
+        assert_eq!(titles(&ui), vec!["first 3"]);
+        assert_eq!(ui.history, vec![track("first", 1)]);
+        assert_eq!(ui.next_sent, None);
+        assert_eq!(played(&commands), vec!["first 1"]);
+    }
+
+    #[test]
+    fn tracks_that_ran_on_before_another_was_picked_are_ignored() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+        ui.sync_next();
+        let before = ui.generation;
+
+        // The audio thread runs on just as another track is picked
+        ui.play(track("second", 1));
+        ui.on_player_event(PlayerEvent::Started(track("first", 2), before));
+        assert_eq!(ui.now_playing, track("second", 1));
+        assert_eq!(titles(&ui), vec!["first 2"]);
+
+        ui.on_player_event(PlayerEvent::Finished(track("second", 1), ui.generation));
+        assert_eq!(ui.now_playing, track("first", 2));
+        assert!(ui.queue.is_empty());
+        assert_eq!(played(&commands), vec!["first 1", "second 1", "first 2"]);
+    }
+
+    #[test]
+    fn repeat_one_plays_the_track_again() {
+        let (mut ui, commands) = ui();
+        ui.queue.repeat = RepeatMode::One;
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+        ui.sync_next();
+        assert_eq!(ui.next_sent, Some(track("first", 1)));
+        assert!(!ui.next_from_queue);
+
+        ui.on_player_event(PlayerEvent::Finished(track("first", 1), ui.generation));
+        assert_eq!(played(&commands), vec!["first 1", "first 1"]);
+        assert_eq!(titles(&ui), vec!["first 2"]);
+        assert!(ui.history.is_empty());
+    }
+
+    #[test]
+    fn finished_tracks_that_are_no_longer_playing_are_ignored() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+
+        ui.on_player_event(PlayerEvent::Finished(track("second", 1), ui.generation));
+        assert_eq!(ui.now_playing, track("first", 1));
+        assert_eq!(played(&commands), vec!["first 1"]);
+    }
+
+    #[test]
+    fn queue_end_repeat_plays_the_run_again() {
+        let (mut ui, commands) = ui();
+        ui.queue_end = QueueEnd::Repeat;
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+
+        ui.on_player_event(PlayerEvent::Finished(track("first", 1), ui.generation));
+        ui.on_player_event(PlayerEvent::Finished(track("first", 2), ui.generation));
+        assert_eq!(played(&commands), vec!["first 1", "first 2", "first 1"]);
+        assert_eq!(titles(&ui), vec!["first 2"]);
+    }
+
+    #[test]
+    fn queue_end_next_album_goes_on_with_the_next_album() {
+        let (mut ui, commands) = ui();
+        ui.queue_end = QueueEnd::NextAlbum;
+        ui.play(track("first", 3));
+
+        ui.on_player_event(PlayerEvent::Finished(track("first", 3), ui.generation));
+        assert_eq!(played(&commands), vec!["first 3", "second 1"]);
+        assert_eq!(titles(&ui), vec!["second 2"]);
+    }
+
+    #[test]
+    fn queue_end_random_plays_one_radio_track() {
+        let (mut ui, commands) = ui();
+        ui.queue_end = QueueEnd::Random;
+        ui.play(track("first", 1));
+
+        ui.on_player_event(PlayerEvent::Finished(track("first", 1), ui.generation));
+        let played = played(&commands);
+        assert_eq!(played.len(), 2);
+        assert_eq!(ui.now_playing.title, played[1]);
+        assert!(ui.radio.is_some());
+        assert!(!ui.queue.is_empty());
+    }
+
+    #[test]
+    fn queue_end_stop_leaves_nothing_playing() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+
+        ui.on_player_event(PlayerEvent::Finished(track("first", 1), ui.generation));
+        assert_eq!(played(&commands), vec!["first 1"]);
+        assert!(ui.now_playing.file_path.is_empty());
+        assert!(ui.queue_run.is_empty());
+    }
+
+    #[test]
+    fn unplayable_tracks_are_marked_and_skipped() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+        ui.queue.add(track("first", 1));
+        ui.queue.add(track("first", 3));
+
+        ui.on_player_event(PlayerEvent::Unplayable(
+            track("first", 1),
+            "file not found".to_string(),
+        ));
+        assert!(ui.missing.contains(&track("first", 1).file_path));
+        assert_eq!(played(&commands), vec!["first 1", "first 2"]);
+        assert_eq!(titles(&ui), vec!["first 3"]);
+        assert!(!ui.queue_run.contains(&track("first", 1)));
+    }
+
+    #[test]
+    fn a_missing_restored_track_is_skipped_without_starting_playback() {
+        let (mut ui, commands) = ui();
+        let session = Session {
+            queue: vec![track("first", 2)],
+            now_playing: Some(track("first", 1)),
+            position: 30_000,
+        };
+        let (restored, _) = ui.restore(session, true).unwrap();
+        assert_eq!(restored, track("first", 1));
+
+        ui.on_player_event(PlayerEvent::Unplayable(
+            track("first", 1),
+            "file not found".to_string(),
+        ));
+        assert_eq!(ui.now_playing, track("first", 2));
+        assert!(!ui.clock.is_running());
+        let commands: Vec<PlayerCommand> = commands.try_iter().collect();
+        assert!(
+            matches!(commands.as_slice(), [PlayerCommand::Play(t, _), PlayerCommand::Pause] if *t == track("first", 2))
+        );
+    }
+
+    #[test]
+    fn unplayable_lined_up_tracks_leave_the_playing_one_alone() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.queue.add(track("first", 2));
+
+        ui.on_player_event(PlayerEvent::Unplayable(
+            track("first", 2),
+            "could not be decoded".to_string(),
+        ));
+        assert_eq!(ui.now_playing, track("first", 1));
+        assert!(ui.queue.is_empty());
+        assert_eq!(played(&commands), vec!["first 1"]);
+    }
+
+    #[test]
+    fn previous_keeps_the_history_selection_in_range() {
+        let (mut ui, commands) = ui();
+        ui.play(track("first", 1));
+        ui.play(track("first", 2));
+        ui.play(track("first", 3));
+        ui.history_select = 1;
+
+        ui.play_previous();
+        assert_eq!(ui.now_playing, track("first", 2));
+        assert_eq!(ui.history, vec![track("first", 1)]);
+        assert_eq!(ui.history_select, 0);
+        assert_eq!(titles(&ui), vec!["first 3"]);
+        assert_eq!(played(&commands).len(), 4);
+    }
+
+    #[test]
+    fn search_says_when_terms_are_left_out() {
+        let (mut ui, _commands) = ui();
+        ui.search_input = "album:second year:2001".to_string();
+        ui.search();
+        assert_eq!(ui.search_results.len(), 1);
+        assert!(ui.current_status().unwrap().contains("only the first"));
+
+        ui.status = None;
+        ui.search_input = "year:2001".to_string();
+        ui.search();
+        assert!(ui.current_status().unwrap().contains("needs a title:"));
+
+        ui.status = None;
+        ui.search_input = "artist:artist".to_string();
+        ui.search();
+        assert!(ui.current_status().is_none());
+    }
+
+    #[test]
+    fn a_filter_that_matches_nothing_is_cleared() {
+        let (mut ui, _commands) = ui();
+        ui.lib_cols.current_active = 1;
+        ui.lib_cols.start_filter();
+        ui.lib_cols.push_input('e');
+        assert_eq!(ui.lib_cols.albums.items.len(), 1);
+        assert!(!ui.lib_cols.no_matches);
+
+        ui.lib_cols.push_input('x');
+        assert_eq!(ui.lib_cols.albums.items.len(), 2);
+        assert!(ui.lib_cols.albums.filter.is_empty());
+        assert!(ui.lib_cols.no_matches);
+
+        ui.lib_cols.pop_input();
+        ui.lib_cols.cancel_input();
+        assert_eq!(ui.lib_cols.albums.items.len(), 2);
+        assert!(ui.lib_cols.input.is_none());
+        assert!(!ui.lib_cols.no_matches);
     }
 }
@@ -6,9 +6,9 @@ mod util;
 use std::io;
 use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use crossbeam_channel as channel;
 use termion::event::Key;
 use termion::raw::IntoRawMode;
@@ -19,10 +19,20 @@ use tui::widgets::{Block, Widget};
 use tui::Terminal;
 
 use crate::application::config::Config;
-use crate::application::state::{Audio, UI};
+use crate::application::loudness::analyze_library;
+use crate::application::output::open_output;
+use crate::application::player::{Audio, Normalization};
+use crate::application::state::{PromptKind, UI};
 use crate::storage::database::*;
+use crate::storage::plays::PlayLog;
+use crate::storage::ratings::Ratings;
+use crate::storage::session::Session;
+use crate::storage::smart::load_smart_playlists;
 use crate::util::event::{Event, Events};
 
+// How often the queue and play counts are saved in case sonik doesn't exit cleanly
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
 fn main() -> Result<(), failure::Error> {
     let matches = App::new("sonik")
         .version("0.9")
@@ -36,73 +46,139 @@ fn main() -> Result<(), failure::Error> {
                 .help("Create the database using files from this location")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("playlist")
+                .short("p")
+                .long("playlist")
+                .value_name("FILE")
+                .help("Add the tracks of an M3U, M3U8 or PLS playlist to the queue")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("rebuild")
                 .short("r")
                 .long("rebuild")
                 .help("Rebuild the library using location from config file"),
         )
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Measure the loudness of albums without ReplayGain values")
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("Measure every album, even ones that have values"),
+                )
+                .arg(
+                    Arg::with_name("write_tags")
+                        .short("w")
+                        .long("write-tags")
+                        .help("Write the values to the tags of MP3 files as well"),
+                ),
+        )
         .get_matches();
 
     println!("Loading configuration...");
 
-    let config = match matches.value_of("music_location") {
+    let mut config = match matches.value_of("music_location") {
         Some(ml) => Config::new(ml).expect("Could not get or create configuration"),
         _ => Config::get_config().expect("Could not get or create configuration"),
     };
 
     println!("Loading database...");
 
-    let (artists, stats) = if !Path::new(&config.database_path).exists() {
+    let (mut artists, stats) = if !Path::new(&config.database_path).exists() {
         create_and_load_database(&config).expect("Could not create database")
     } else if matches.is_present("rebuild") {
         rebuild_database(&config).expect("Could not rebuild database")
     } else {
-        load_database(&config).expect("Could not load database")
+        load_database(&config).unwrap_or_else(|()| {
+            println!("The library was saved by another version of sonik, rebuilding...");
+            rebuild_database(&config).expect("Could not rebuild database")
+        })
     };
 
+    if let Some(analyze) = matches.subcommand_matches("analyze") {
+        let (measured, failed) = analyze_library(
+            &mut artists,
+            analyze.is_present("all"),
+            analyze.is_present("write_tags"),
+        );
+        save_database(&config, &artists, &stats);
+        println!("Analyzed {} tracks, {} could not be read", measured, failed);
+
+        return Ok(());
+    }
+
     let engine_group =
-        create_fuzzy_searcher(&artists).expect("Could not create artist fuzzy search");
+        load_fuzzy_searcher(&config, &artists).expect("Could not create artist fuzzy search");
+
+    let plays = PlayLog::load(&config.plays_path());
+    let ratings = Ratings::load(&config.ratings_path());
+    // A mistake in the hand-edited file shouldn't keep sonik from starting
+    let smart_playlists = load_smart_playlists(&config.smart_playlists_path());
+    if smart_playlists.is_err() {
+        println!(
+            "Error: Could not read {}, carrying on without smart playlists",
+            config.smart_playlists_path()
+        );
+    }
 
-    // Create the sink for the audio output device
-    let device = rodio::default_output_device().expect("No audio output device found");
+    // Sonik still runs without a sound card, it just doesn't make a sound
+    let output = open_output(config.output, &config.output_file());
 
-    // Create the notification channel for empty
-    // audio sink, pause/play events, and the track transfer channel
-    let (btx, brx) = channel::bounded(0);
-    let (ptx, prx) = channel::bounded(0);
-    let (ttx, trx) = channel::bounded(0);
+    // Commands go to the audio thread and events come back; neither
+    // side waits on the other, as the audio thread can take a while
+    // opening a track and the UI only looks in between keypresses
+    let (command_tx, command_rx) = channel::unbounded();
+    let (event_tx, event_rx) = channel::unbounded();
 
     // Keypress event handler, spins a thread
     let ui_events = Events::new();
 
     // Create structs to be managed on different threads
-    let mut ui = UI::new(&artists, brx, ttx, ptx, engine_group, stats);
-    let mut audio = Audio::new(device, trx, btx, prx);
+    let mut ui = UI::new(
+        &artists,
+        command_tx,
+        event_rx,
+        engine_group,
+        stats,
+        plays,
+        ratings,
+        smart_playlists.as_ref().map(|p| &p[..]).unwrap_or(&[]),
+        &config.playlists_folder(),
+    );
+    ui.queue.spread = config.shuffle_spread;
+    if smart_playlists.is_err() {
+        ui.set_status("could not read the smart playlists file".to_string());
+    }
+    ui.radio_window = config.radio_window;
+    ui.queue_end = config.queue_end;
+    ui.seek_step = Duration::from_secs(config.seek_step);
+    let mut audio = Audio::new(output, command_rx, event_tx);
+    ui.volume = config.volume;
+    audio.set_volume(config.volume);
+    audio.set_crossfade(Duration::from_secs(config.crossfade), config.prefer_gapless);
+    audio.set_normalization(Normalization {
+        mode: config.replay_gain,
+        preamp: config.replay_gain_preamp,
+        untagged: config.untagged_gain,
+    });
 
-    // All audio-related bits are sent to their own thread
-    thread::spawn(move || {
-        loop {
-            // Alert the UI thread that there is no song playing
-            if audio.sink.empty() {
-                if let Ok(()) = audio.btx.send_timeout(true, Duration::from_millis(250)) {}
-            } else if let Ok(()) = audio.btx.send_timeout(false, Duration::from_millis(250)) {
-            }
+    // Bring back the queue from last time, and the track that was playing
+    let session = Session::load(&config.session_path());
+    if let Some((track, position)) = ui.restore(session, config.resume_playback) {
+        if audio.play_from(track, position) {
+            audio.pause();
+        }
+    }
 
-            // If the UI thread semds a track from the queue,
-            // receive it and send it to the sink
-            if let Ok(track) = audio.trx.try_recv() {
-                audio.play(track)
-            }
+    if let Some(playlist) = matches.value_of("playlist") {
+        ui.load_playlist(Path::new(playlist));
+    }
 
-            // Listen for a play/pause event
-            match audio.prx.try_recv() {
-                Ok(true) => audio.pause_play(),
-                Ok(false) => audio.stop(),
-                _ => {}
-            }
-        }
-    });
+    // All audio-related bits are sent to their own thread
+    thread::spawn(move || audio.run());
 
     let stdout = io::stdout().into_raw_mode()?;
     let backend = TermionBackend::new(stdout);
@@ -110,6 +186,8 @@ fn main() -> Result<(), failure::Error> {
     terminal.hide_cursor()?;
     terminal.clear()?;
 
+    let mut last_save = Instant::now();
+
     loop {
         terminal.draw(|mut f| {
             let size = f.size();
@@ -132,6 +210,8 @@ fn main() -> Result<(), failure::Error> {
                 0 => ui::screens::draw_queue(&mut f, &ui, chunks[1]),
                 1 => ui::screens::draw_library(&mut f, &ui, chunks[1]),
                 2 => ui::screens::draw_search(&mut f, &ui, chunks[1]),
+                3 => ui::screens::draw_playlists(&mut f, &ui, chunks[1]),
+                4 => ui::screens::draw_history(&mut f, &ui, chunks[1]),
                 _ => {}
             }
             ui::screens::draw_stats(&mut f, &ui, chunks[2]);
@@ -140,6 +220,22 @@ fn main() -> Result<(), failure::Error> {
         // Capture keypresses
         if let Event::Input(input) = ui_events.next()? {
             match input {
+                // Prompts take every key until they are finished or cancelled
+                key if ui.prompt.is_some() => match key {
+                    Key::Char('\n') => ui.finish_prompt(),
+                    Key::Esc => ui.cancel_prompt(),
+                    Key::Char(c) => ui.push_prompt(c),
+                    Key::Backspace => ui.pop_prompt(),
+                    _ => {}
+                },
+                // Typing a jump or filter in the library takes every key
+                key if ui.tabs.index == 1 && ui.lib_cols.input.is_some() => match key {
+                    Key::Char('\n') => ui.lib_cols.finish_input(),
+                    Key::Esc => ui.lib_cols.cancel_input(),
+                    Key::Char(c) => ui.lib_cols.push_input(c),
+                    Key::Backspace => ui.lib_cols.pop_input(),
+                    _ => {}
+                },
                 Key::Char('p') => {
                     if ui.tabs.index == 2 {
                         ui.search_input.push('p');
@@ -147,30 +243,143 @@ fn main() -> Result<(), failure::Error> {
                         ui.pause_play();
                     }
                 }
-                Key::Esc => {
-                    // Clear buffer so command line prompt is shown correctly
-                    terminal.clear()?;
-                    break;
-                }
+                Key::Esc => ui.should_quit = true,
                 Key::Char('s') => {
                     if ui.tabs.index == 2 {
                         ui.search_input.push('s');
                     } else {
                         // Shuffle queue in place
-                        ui.queue.shuffle();
+                        ui.shuffle_queue();
+                    }
+                }
+                Key::Char('S') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('S');
+                    } else {
+                        ui.cycle_shuffle_mode();
+                    }
+                }
+                Key::Char('u') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('u');
+                    } else {
+                        // Put the queue back in the order it was before shuffling
+                        ui.unshuffle_queue();
+                    }
+                }
+                Key::Char('R') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('R');
+                    } else {
+                        // Keep the queue filled with random tracks
+                        ui.toggle_radio();
+                    }
+                }
+                Key::Char('.') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('.');
+                    } else {
+                        ui.toggle_stop_after_current();
+                    }
+                }
+                Key::Char('E') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('E');
+                    } else {
+                        // Choose what happens when the queue runs out
+                        ui.cycle_queue_end();
+                    }
+                }
+                Key::Char(']') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push(']');
+                    } else {
+                        ui.seek_forward();
+                    }
+                }
+                Key::Char('[') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('[');
+                    } else {
+                        ui.seek_backward();
+                    }
+                }
+                Key::Char('g') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('g');
+                    } else if !ui.now_playing.file_path.is_empty() {
+                        // Go to a percentage or timestamp in the track
+                        ui.start_prompt(PromptKind::Seek);
+                    }
+                }
+                Key::Char('+') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('+');
+                    } else {
+                        ui.volume_up();
+                    }
+                }
+                Key::Char('=') => {
+                    // Same key as + without shift
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('=');
+                    } else {
+                        ui.volume_up();
+                    }
+                }
+                Key::Char('-') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('-');
+                    } else {
+                        ui.volume_down();
+                    }
+                }
+                Key::Char('m') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('m');
+                    } else {
+                        ui.toggle_mute();
+                    }
+                }
+                Key::Char('z') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('z');
+                    } else {
+                        // Take back the last change to the queue
+                        ui.undo();
+                    }
+                }
+                Key::Char('y') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('y');
+                    } else {
+                        ui.redo();
+                    }
+                }
+                Key::Char('*') => {
+                    if ui.tabs.index == 2 {
+                        ui.search_input.push('*');
+                    } else {
+                        // Give the playing track another star
+                        ui.rate_now_playing();
                     }
                 }
                 Key::Char('r') => {
                     if ui.tabs.index == 2 {
                         ui.search_input.push('r');
                     } else {
-                        // Turn on repeat
+                        // Cycle through the repeat modes
+                        ui.cycle_repeat();
                     }
                 }
                 Key::Char('>') => {
                     // Skip to next song
                     ui.play_from_queue();
                 }
+                Key::Char('<') => {
+                    // Go back to the previous song
+                    ui.play_previous();
+                }
                 Key::Char(' ') => {
                     if ui.tabs.index == 2 {
                         if !ui.search_results.is_empty() {
@@ -202,18 +411,104 @@ fn main() -> Result<(), failure::Error> {
                 Key::Char('1') => ui.tabs.index = 0,
                 Key::Char('2') => ui.tabs.index = 1,
                 Key::Char('3') => ui.tabs.index = 2,
-                Key::Up => {
+                Key::Char('4') => {
+                    ui.tabs.index = 3;
+                    ui.reload_playlists(ui.playlists.selected);
+                }
+                Key::Char('5') => ui.tabs.index = 4,
+                Key::Char('d') => {
+                    if ui.tabs.index == 0 {
+                        // Remove the selected track from the queue
+                        ui.remove_from_queue();
+                    } else if ui.tabs.index == 3 {
+                        ui.start_delete_playlist();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('d');
+                    }
+                }
+                Key::Char('K') => {
+                    if ui.tabs.index == 0 {
+                        ui.move_up_in_queue();
+                    } else if ui.tabs.index == 3 {
+                        ui.move_playlist_up();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('K');
+                    }
+                }
+                Key::Char('J') => {
+                    if ui.tabs.index == 0 {
+                        ui.move_down_in_queue();
+                    } else if ui.tabs.index == 3 {
+                        ui.move_playlist_down();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('J');
+                    }
+                }
+                Key::Char('w') => {
+                    if ui.tabs.index == 0 {
+                        // Write the queue out as a playlist
+                        ui.start_prompt(PromptKind::SavePlaylist);
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('w');
+                    }
+                }
+                Key::Char('t') => {
+                    if ui.tabs.index == 0 {
+                        ui.move_to_top_of_queue();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('t');
+                    }
+                }
+                Key::Char('N') => {
+                    if ui.tabs.index == 3 {
+                        ui.start_prompt(PromptKind::NewPlaylist);
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('N');
+                    }
+                }
+                Key::Char('e') => {
+                    if ui.tabs.index == 3 {
+                        ui.start_rename_playlist();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('e');
+                    }
+                }
+                Key::Char('a') => {
                     if ui.tabs.index == 1 {
+                        // Add the selection to the playlist picked in the playlists tab
+                        ui.add_to_playlist();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('a');
+                    }
+                }
+                // Letters are taken by the search box, so this works there too
+                Key::Ctrl('a') if ui.tabs.index == 1 || ui.tabs.index == 2 => {
+                    ui.add_to_playlist();
+                }
+                Key::Up => {
+                    if ui.tabs.index == 0 {
+                        ui.on_up_queue();
+                    } else if ui.tabs.index == 1 {
                         ui.lib_cols.on_up();
                     } else if ui.tabs.index == 2 {
                         ui.on_up_search();
+                    } else if ui.tabs.index == 3 {
+                        ui.on_up_playlists();
+                    } else if ui.tabs.index == 4 {
+                        ui.on_up_history();
                     }
                 }
                 Key::Down => {
-                    if ui.tabs.index == 1 {
+                    if ui.tabs.index == 0 {
+                        ui.on_down_queue();
+                    } else if ui.tabs.index == 1 {
                         ui.lib_cols.on_down();
                     } else if ui.tabs.index == 2 {
                         ui.on_down_search();
+                    } else if ui.tabs.index == 3 {
+                        ui.on_down_playlists();
+                    } else if ui.tabs.index == 4 {
+                        ui.on_down_history();
                     }
                 }
                 Key::Left => {
@@ -229,6 +524,22 @@ fn main() -> Result<(), failure::Error> {
                 Key::Char('\n') => {
                     ui.on_enter();
                 }
+                Key::Char('/') => {
+                    if ui.tabs.index == 1 {
+                        // Narrow down the active column
+                        ui.lib_cols.start_filter();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('/');
+                    }
+                }
+                Key::Char('j') => {
+                    if ui.tabs.index == 1 {
+                        // Jump to the first entry starting with what is typed next
+                        ui.lib_cols.start_jump();
+                    } else if ui.tabs.index == 2 {
+                        ui.search_input.push('j');
+                    }
+                }
                 Key::Char(c) => {
                     if ui.tabs.index == 2 {
                         ui.search_input.push(c);
@@ -243,13 +554,29 @@ fn main() -> Result<(), failure::Error> {
             }
         }
 
-        // Check for notifications that there is no audio being played
-        if let Ok(true) = ui.rx.recv_timeout(Duration::from_millis(250)) {
-            if ui.queue.is_empty() {
-                ui.blank_now_play();
-            } else {
-                ui.play_from_queue();
+        // Catch up on what the audio thread has been up to
+        while let Ok(event) = ui.events.try_recv() {
+            ui.on_player_event(event);
+        }
+        ui.play_if_idle();
+        ui.sync_next();
+
+        if ui.should_quit {
+            // Clear buffer so command line prompt is shown correctly
+            terminal.clear()?;
+            if ui.save(&config).is_err() {
+                println!("Error: Could not save queue and play counts");
+            }
+            config.volume = ui.volume;
+            if config.save().is_err() {
+                println!("Error: Could not save volume");
             }
+            break;
+        }
+
+        if last_save.elapsed() >= SAVE_INTERVAL {
+            ui.save(&config).ok();
+            last_save = Instant::now();
         }
     }
     Ok(())
@@ -1,24 +1,42 @@
 use std::fs;
 use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
 use bincode::{deserialize_from, serialize_into};
 use ignore::{DirEntry, Walk};
-use simsearch::SimSearch;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::application::config::Config;
+use crate::application::loudness::analyze_library;
+use crate::storage::index::{Hit, SearchIndex};
+use crate::storage::playlist::{import_playlists, is_playlist};
 use crate::storage::record::{Album, Artist, Stats, Track};
 use crate::storage::terms::{SearchQuery, Term};
 
+// Written at the start of the library database so that one saved in an older
+// format gets rebuilt instead of misread. The version goes up whenever
+// Artist, Album or Track change.
+const DATABASE_MAGIC: [u8; 8] = *b"sonikdb\0";
+const DATABASE_VERSION: u32 = 1;
+
+// The saved search index holds names already normalized and split into
+// words, so it goes up whenever text.rs or index.rs change what they
+// produce, and the index is built again
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
 pub struct EngineGroup {
     pub artists: Engine,
     pub albums: Engine,
     pub tracks: Engine,
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum Engine {
-    Artists(SimSearch<usize>),
-    Albums(SimSearch<(usize, usize)>),
-    Tracks(SimSearch<(usize, usize, usize)>),
+    Artists(SearchIndex<usize>),
+    Albums(SearchIndex<(usize, usize)>),
+    Tracks(SearchIndex<(usize, usize, usize)>),
 }
 
 impl Engine {
@@ -31,9 +49,9 @@ impl Engine {
     }
 }
 
-type ArtistResult = Vec<usize>;
-type AlbumResult = Vec<(usize, usize)>;
-type TrackResult = Vec<(usize, usize, usize)>;
+type ArtistResult = Vec<Hit<usize>>;
+type AlbumResult = Vec<Hit<(usize, usize)>>;
+type TrackResult = Vec<Hit<(usize, usize, usize)>>;
 
 pub enum SearchResult {
     Artists(ArtistResult),
@@ -64,6 +82,7 @@ pub fn create_and_load_database(config: &Config) -> Result<(Vec<Artist>, Stats),
     // create vector of artists
     let mut artists: Vec<Artist> = Vec::new();
     let mut stats = Stats::new().unwrap();
+    let mut playlists: Vec<PathBuf> = Vec::new();
 
     // Walk through the music directory and add paths for each track
     for result in Walk::new(&config.music_folder) {
@@ -73,10 +92,29 @@ pub fn create_and_load_database(config: &Config) -> Result<(Vec<Artist>, Stats),
                     Ok(t) => add_to_database_helper(t, &mut artists, &mut stats),
                     _ => println!("{}", entry.path().to_str().unwrap()),
                 }
+            } else if is_playlist(entry.path()) {
+                playlists.push(entry.path().to_path_buf());
             }
         }
     }
 
+    // Sort for easy finding in the UI
+    artists.sort_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
+
+    // Playlists in the music folder can only be matched up once every track is known
+    import_playlists(&playlists, &artists, &config.playlists_folder());
+
+    // Measuring the loudness of a whole library takes a long time, so it's optional
+    if config.analyze_on_scan {
+        analyze_library(&mut artists, false, false);
+    }
+
+    save_database(config, &artists, &stats);
+
+    Ok((artists, stats))
+}
+
+pub fn save_database(config: &Config, artists: &[Artist], stats: &Stats) {
     let mut f = BufWriter::new(
         fs::File::create(&config.database_path).expect("Could not write to database path"),
     );
@@ -85,25 +123,24 @@ pub fn create_and_load_database(config: &Config) -> Result<(Vec<Artist>, Stats),
         fs::File::create(&config.stats_path).expect("Could not write to stats path"),
     );
 
-    // Sort for easy finding in the UI
-    artists.sort_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
-
-    serialize_into(&mut f, &artists).expect("Could not serialize database to file");
-    serialize_into(&mut g, &stats).expect("Could not serialize stats to file");
-
-    Ok((artists, stats))
+    serialize_into(&mut f, &(DATABASE_MAGIC, DATABASE_VERSION))
+        .expect("Could not serialize database to file");
+    serialize_into(&mut f, artists).expect("Could not serialize database to file");
+    serialize_into(&mut g, stats).expect("Could not serialize stats to file");
 }
 
+// Fails if the database can't be read or was saved in another format
 pub fn load_database(config: &Config) -> Result<(Vec<Artist>, Stats), ()> {
-    let mut library_reader = BufReader::new(
-        fs::File::open(&config.database_path).expect("Could not open database file"),
-    );
+    let mut library_reader = BufReader::new(fs::File::open(&config.database_path).map_err(|_| ())?);
+    let mut stats_reader = BufReader::new(fs::File::open(&config.stats_path).map_err(|_| ())?);
 
-    let mut stats_reader =
-        BufReader::new(fs::File::open(&config.stats_path).expect("Could not open database file"));
+    let header: ([u8; 8], u32) = deserialize_from(&mut library_reader).map_err(|_| ())?;
+    if header != (DATABASE_MAGIC, DATABASE_VERSION) {
+        return Err(());
+    }
 
-    let artists = deserialize_from(&mut library_reader).expect("Could not deserialize");
-    let stats = deserialize_from(&mut stats_reader).expect("Could not deserialize");
+    let artists = deserialize_from(&mut library_reader).map_err(|_| ())?;
+    let stats = deserialize_from(&mut stats_reader).map_err(|_| ())?;
 
     Ok((artists, stats))
 }
@@ -160,7 +197,7 @@ fn add_to_database(
                         Album::new(album_title.to_string(), artist_name.to_string(), album_year)
                             .unwrap();
                     //debug - println!("Created new album: {}", album_title);
-                    album.tracks.push(t);
+                    if let Ok(()) = album.update_album(t) {}
                     if let Ok(()) = artists[idx].add_album(album) {}
                     stats.albums += 1;
                 }
@@ -176,7 +213,7 @@ fn add_to_database(
             let mut album =
                 Album::new(album_title.to_string(), artist_name.to_string(), album_year).unwrap();
             //debug - println!("Created new album: {}", &album.title);
-            album.tracks.push(t);
+            if let Ok(()) = album.update_album(t) {}
             if let Ok(()) = artist.add_album(album) {}
             stats.albums += 1;
             artists.push(artist);
@@ -185,10 +222,12 @@ fn add_to_database(
 }
 
 pub fn create_fuzzy_searcher(records: &[Artist]) -> Result<EngineGroup, ()> {
-    let mut artists: SimSearch<usize> = SimSearch::new();
-    let mut albums: SimSearch<(usize, usize)> = SimSearch::new();
-    let mut tracks: SimSearch<(usize, usize, usize)> = SimSearch::new();
+    let mut artists: SearchIndex<usize> = SearchIndex::new();
+    let mut albums: SearchIndex<(usize, usize)> = SearchIndex::new();
+    let mut tracks: SearchIndex<(usize, usize, usize)> = SearchIndex::new();
 
+    // The index normalizes names itself, the indices are
+    // used to get back to the original records for display
     for (i, record) in (&records).iter().enumerate() {
         let artist_name = &record.title;
         artists.insert(i, &artist_name);
@@ -209,10 +248,61 @@ pub fn create_fuzzy_searcher(records: &[Artist]) -> Result<EngineGroup, ()> {
     })
 }
 
-pub fn search(engine: &EngineGroup, query: SearchQuery) -> SearchResult {
-    match query.terms {
-        Term::Title(s) => engine.tracks.search(s.as_str()),
-        Term::Album(s) => engine.albums.search(s.as_str()),
-        Term::Artist(s) => engine.artists.search(s.as_str()),
+// The saved index remembers which database it was built from, and how,
+// so that it is thrown away whenever the library is rebuilt or the
+// index format changes
+#[derive(Serialize, Deserialize)]
+struct SavedIndex {
+    stamp: (u32, u64, u64, u32),
+    engines: EngineGroup,
+}
+
+fn database_stamp(config: &Config) -> Option<(u32, u64, u64, u32)> {
+    let metadata = fs::metadata(&config.database_path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some((
+        INDEX_VERSION,
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos(),
+    ))
+}
+
+pub fn load_fuzzy_searcher(config: &Config, records: &[Artist]) -> Result<EngineGroup, ()> {
+    let stamp = database_stamp(config);
+
+    if let Ok(f) = fs::File::open(config.index_path()) {
+        if let Ok(saved) = deserialize_from::<_, SavedIndex>(&mut BufReader::new(f)) {
+            if Some(saved.stamp) == stamp {
+                return Ok(saved.engines);
+            }
+        }
+    }
+
+    // Missing, unreadable or out of date, so build it again and save it for next time
+    let engines = create_fuzzy_searcher(records)?;
+
+    if let Some(stamp) = stamp {
+        let saved = SavedIndex { stamp, engines };
+        if let Ok(f) = fs::File::create(config.index_path()) {
+            if serialize_into(&mut BufWriter::new(f), &saved).is_err() {
+                println!("Error: Could not save search index");
+            }
+        }
+        return Ok(saved.engines);
     }
+
+    Ok(engines)
+}
+
+pub fn search(engine: &EngineGroup, query: SearchQuery) -> Option<SearchResult> {
+    // Only the first text term is fuzzy searched; the other
+    // terms are for filtering the library in saved queries
+    query.terms.iter().find_map(|term| match term {
+        Term::Title(s) => Some(engine.tracks.search(s.as_str())),
+        Term::Album(s) => Some(engine.albums.search(s.as_str())),
+        Term::Artist(s) => Some(engine.artists.search(s.as_str())),
+        _ => None,
+    })
 }
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
+use strsim::levenshtein;
+
+use crate::storage::text::normalize;
+
+// Misspelled tokens less similar than this are not considered a match
+const THRESHOLD: f64 = 0.75;
+
+// A search result along with how well it matched and which
+// character ranges of the original name were matched
+#[derive(Clone)]
+pub struct Hit<I> {
+    pub item: I,
+    pub score: f64,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+impl<I> Hit<I> {
+    pub fn map<J, F>(self, f: F) -> Hit<J>
+    where
+        F: FnOnce(I) -> J,
+    {
+        Hit {
+            item: f(self.item),
+            score: self.score,
+            ranges: self.ranges,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<I> {
+    item: I,
+    // Character ranges in the original name for each indexed word
+    words: Vec<(usize, usize)>,
+}
+
+// A small fuzzy search engine in the spirit of simsearch, which
+// also keeps track of scores and where each match came from. It is
+// saved to disk, so INDEX_VERSION in database.rs goes up whenever
+// what it stores changes.
+#[derive(Serialize, Deserialize)]
+pub struct SearchIndex<I> {
+    entries: Vec<Entry<I>>,
+    // Normalized token to (entry, word) positions
+    tokens: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl<I> Default for SearchIndex<I> {
+    fn default() -> SearchIndex<I> {
+        SearchIndex {
+            entries: Vec::new(),
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl<I> SearchIndex<I>
+where
+    I: Clone,
+{
+    pub fn new() -> SearchIndex<I> {
+        SearchIndex::default()
+    }
+
+    pub fn insert(&mut self, item: I, name: &str) {
+        let entry_idx = self.entries.len();
+        let mut words = Vec::new();
+
+        for (start, end) in word_ranges(name) {
+            let word: String = name.chars().skip(start).take(end - start).collect();
+
+            // Words made only of punctuation fold away to nothing
+            let token = normalize(&word);
+            if token.is_empty() {
+                continue;
+            }
+
+            self.tokens
+                .entry(token)
+                .or_default()
+                .push((entry_idx, words.len()));
+            words.push((start, end));
+        }
+
+        self.entries.push(Entry { item, words });
+    }
+
+    // Returns hits sorted from best to worst match
+    pub fn search(&self, query: &str) -> Vec<Hit<I>> {
+        let mut patterns = normalize(query)
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        patterns.sort();
+        patterns.dedup();
+
+        if patterns.is_empty() {
+            return Vec::new();
+        }
+
+        // Best score for each pattern within each entry, plus matched words
+        let mut entry_scores: HashMap<usize, (Vec<f64>, Vec<usize>)> = HashMap::new();
+
+        for (p_idx, pattern) in patterns.iter().enumerate() {
+            for (token, positions) in &self.tokens {
+                let score = match token_score(token, pattern) {
+                    Some(s) => s,
+                    None => continue,
+                };
+
+                for &(entry_idx, word_idx) in positions {
+                    let (scores, words) = entry_scores
+                        .entry(entry_idx)
+                        .or_insert_with(|| (vec![0.; patterns.len()], Vec::new()));
+                    if score > scores[p_idx] {
+                        scores[p_idx] = score;
+                    }
+                    if !words.contains(&word_idx) {
+                        words.push(word_idx);
+                    }
+                }
+            }
+        }
+
+        let mut hits = entry_scores
+            .into_iter()
+            .map(|(entry_idx, (scores, mut words))| {
+                let entry = &self.entries[entry_idx];
+                words.sort();
+
+                let hit = Hit {
+                    item: entry.item.clone(),
+                    score: scores.iter().sum::<f64>() / patterns.len() as f64,
+                    ranges: words.iter().map(|w| entry.words[*w]).collect(),
+                };
+                (entry_idx, hit)
+            })
+            .collect::<Vec<(usize, Hit<I>)>>();
+
+        // Ties keep library order so results don't jump around between searches
+        hits.sort_by(|a, b| {
+            b.1.score
+                .partial_cmp(&a.1.score)
+                .unwrap()
+                .then(a.0.cmp(&b.0))
+        });
+
+        hits.into_iter().map(|(_, hit)| hit).collect()
+    }
+}
+
+// Exact matches beat prefixes, which beat misspellings
+fn token_score(token: &str, pattern: &str) -> Option<f64> {
+    if token == pattern {
+        return Some(1.);
+    }
+
+    if token.starts_with(pattern) {
+        return Some(0.9);
+    }
+
+    // Like simsearch, a token longer than the pattern is not penalized
+    // for the extra characters, only for the ones that differ
+    let pattern_len = pattern.chars().count();
+    let len_diff = token.chars().count().saturating_sub(pattern_len);
+    let distance = levenshtein(token, pattern).saturating_sub(len_diff);
+    let similarity = 1. - distance as f64 / pattern_len as f64;
+
+    if similarity >= THRESHOLD {
+        Some(0.8 * similarity)
+    } else {
+        None
+    }
+}
+
+// Character ranges of the whitespace separated words in a name
+fn word_ranges(name: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+
+    for (i, c) in name.chars().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                ranges.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(s) = start {
+        ranges.push((s, name.chars().count()));
+    }
+
+    ranges
+}
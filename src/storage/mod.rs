@@ -1,6 +1,13 @@
 pub mod database;
+pub mod index;
+pub mod playlist;
+pub mod plays;
+pub mod ratings;
 pub mod record;
+pub mod session;
+pub mod smart;
 pub mod terms;
+pub mod text;
 
 fn vec_compare<T: PartialEq>(va: &[T], vb: &[T]) -> bool {
     (va.len() == vb.len()) && va.iter().zip(vb).all(|(a, b)| a == b)
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::storage::record::{Artist, Record, Track};
+use crate::storage::smart::SmartPlaylist;
+
+// Remembers the order playlists were arranged in, one name per line. The
+// leading dot keeps it from ever clashing with a playlist name.
+const ORDER_FILE: &str = ".order";
+
+// A playlist saved as an M3U8 file in the playlist folder
+#[derive(Clone, Debug)]
+pub struct SavedPlaylist {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Record for SavedPlaylist {
+    fn name(&self) -> &str {
+        &self.name[..]
+    }
+}
+
+// Everything listed in the playlists tab
+#[derive(Clone, Debug)]
+pub enum Playlist {
+    Saved(SavedPlaylist),
+    Smart(SmartPlaylist),
+}
+
+impl Record for Playlist {
+    fn name(&self) -> &str {
+        match self {
+            Playlist::Saved(p) => p.name(),
+            Playlist::Smart(p) => p.name(),
+        }
+    }
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => matches!(ext.to_lowercase().as_str(), "m3u" | "m3u8" | "pls"),
+        None => false,
+    }
+}
+
+// Reads the file paths out of an M3U, M3U8 or PLS playlist; relative
+// paths are taken to be relative to the folder the playlist is in
+pub fn read_playlist(path: &Path) -> Result<Vec<PathBuf>, ()> {
+    let bytes = fs::read(path).map_err(|_| ())?;
+    let contents = decode_text(bytes);
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let is_pls = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some(ext) if ext.eq_ignore_ascii_case("pls")
+    );
+
+    let entries = contents
+        .lines()
+        .map(|line| line.trim().trim_start_matches('\u{feff}'))
+        .filter_map(|line| {
+            if is_pls {
+                // Entries look like "File1=/path/to/track.mp3"
+                let mut parts = line.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key.to_lowercase().starts_with("file") => {
+                        Some(value.trim())
+                    }
+                    _ => None,
+                }
+            } else if line.is_empty() || line.starts_with('#') {
+                None
+            } else {
+                Some(line)
+            }
+        })
+        .map(|entry| {
+            let entry = match entry.strip_prefix("file://") {
+                Some(url) => percent_decode(url),
+                None => entry.to_string(),
+            };
+            let entry_path = Path::new(&entry);
+            if entry_path.is_absolute() {
+                entry_path.to_path_buf()
+            } else {
+                base.join(entry_path)
+            }
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+// Plain M3U files are often Latin-1, so anything that isn't UTF-8 is read
+// as Latin-1, where every byte is the character with the same number
+fn decode_text(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    }
+}
+
+// Turns the %20 style escapes of a file URL back into the characters
+// they stand for, leaving anything that isn't a valid escape as it is
+fn percent_decode(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    decode_text(decoded)
+}
+
+// Matches playlist entries up with tracks in the library, first by their
+// full path and then by file name, for playlists made on another machine.
+// Returns the tracks found and how many entries could not be found.
+pub fn resolve_playlist(entries: &[PathBuf], library: &[Artist]) -> (Vec<Track>, usize) {
+    let mut by_path: HashMap<PathBuf, &Track> = HashMap::new();
+    let mut by_name: HashMap<String, Vec<&Track>> = HashMap::new();
+
+    for track in library
+        .iter()
+        .flat_map(|artist| artist.albums.iter())
+        .flat_map(|album| album.tracks.iter())
+    {
+        let path = PathBuf::from(&track.file_path);
+        if let Some(name) = path.file_name() {
+            by_name
+                .entry(name.to_string_lossy().to_lowercase())
+                .or_default()
+                .push(track);
+        }
+        by_path.insert(path, track);
+    }
+
+    let mut tracks = Vec::new();
+    let mut missing = 0;
+
+    for entry in entries {
+        let canonical = fs::canonicalize(entry).unwrap_or_else(|_| entry.clone());
+        let found = by_path
+            .get(entry)
+            .or_else(|| by_path.get(&canonical))
+            .cloned()
+            .or_else(|| {
+                // Only trust a file name if it is unique in the library
+                let name = entry.file_name()?.to_string_lossy().to_lowercase();
+                match by_name.get(&name) {
+                    Some(matches) if matches.len() == 1 => Some(matches[0]),
+                    _ => None,
+                }
+            });
+
+        match found {
+            Some(track) => tracks.push(track.clone()),
+            None => missing += 1,
+        }
+    }
+
+    (tracks, missing)
+}
+
+// Writes an extended M3U playlist, which is always UTF-8
+pub fn write_playlist(path: &Path, tracks: &[Track]) -> Result<(), ()> {
+    let mut f = fs::File::create(path).map_err(|_| ())?;
+
+    writeln!(f, "#EXTM3U").map_err(|_| ())?;
+    for track in tracks {
+        writeln!(
+            f,
+            "#EXTINF:{},{} - {}",
+            track.duration / 1000,
+            track.artist,
+            track.title
+        )
+        .map_err(|_| ())?;
+        writeln!(f, "{}", track.file_path).map_err(|_| ())?;
+    }
+
+    Ok(())
+}
+
+// Turns a playlist name into a path in the playlist folder
+pub fn playlist_path(folder: &str, name: &str) -> Option<PathBuf> {
+    // Keep names from wandering out of the folder
+    let name = name
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\' | '\0'))
+        .collect::<String>();
+    if name.is_empty() || name.starts_with('.') {
+        return None;
+    }
+
+    let mut path = PathBuf::from(folder);
+    path.push(format!("{}.m3u8", name));
+
+    Some(path)
+}
+
+// Copies playlists found while scanning the music folder into the playlist
+// folder, leaving any saved playlist with the same name alone
+pub fn import_playlists(found: &[PathBuf], library: &[Artist], folder: &str) {
+    if found.is_empty() || fs::create_dir_all(folder).is_err() {
+        return;
+    }
+
+    for path in found {
+        let name = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let target = match playlist_path(folder, &name) {
+            Some(t) => t,
+            None => continue,
+        };
+        if target.exists() {
+            continue;
+        }
+
+        if let Ok(entries) = read_playlist(path) {
+            let (tracks, _) = resolve_playlist(&entries, library);
+            if !tracks.is_empty() && write_playlist(&target, &tracks).is_err() {
+                println!("Error: Could not import {}", path.to_string_lossy());
+            }
+        }
+    }
+}
+
+// Lists the saved playlists, in the order they were arranged in; any
+// that were added since then come after, sorted by name
+pub fn list_playlists(folder: &str) -> Vec<SavedPlaylist> {
+    let mut playlists = match fs::read_dir(folder) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|e| e.to_str()),
+                    Some(ext) if ext.eq_ignore_ascii_case("m3u8")
+                )
+            })
+            .filter_map(|path| {
+                let name = path.file_stem()?.to_string_lossy().to_string();
+                Some(SavedPlaylist { name, path })
+            })
+            .collect::<Vec<SavedPlaylist>>(),
+        Err(_) => return Vec::new(),
+    };
+    playlists.sort_by_key(|p| p.name.to_lowercase());
+
+    let order = fs::read_to_string(Path::new(folder).join(ORDER_FILE)).unwrap_or_default();
+    let mut ordered = Vec::new();
+    for name in order.lines() {
+        if let Some(idx) = playlists.iter().position(|p| p.name == name) {
+            ordered.push(playlists.remove(idx));
+        }
+    }
+    ordered.append(&mut playlists);
+
+    ordered
+}
+
+pub fn save_playlist_order(folder: &str, playlists: &[SavedPlaylist]) -> Result<(), ()> {
+    let mut f = fs::File::create(Path::new(folder).join(ORDER_FILE)).map_err(|_| ())?;
+    for playlist in playlists {
+        writeln!(f, "{}", playlist.name).map_err(|_| ())?;
+    }
+
+    Ok(())
+}
+
+// Adds tracks to the end of a saved playlist without rewriting the
+// entries already in it, which may not all be in the library
+pub fn append_to_playlist(path: &Path, tracks: &[Track]) -> Result<(), ()> {
+    if !path.exists() {
+        return write_playlist(path, tracks);
+    }
+
+    let mut f = OpenOptions::new().append(true).open(path).map_err(|_| ())?;
+    for track in tracks {
+        writeln!(
+            f,
+            "#EXTINF:{},{} - {}",
+            track.duration / 1000,
+            track.artist,
+            track.title
+        )
+        .map_err(|_| ())?;
+        writeln!(f, "{}", track.file_path).map_err(|_| ())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_latin1_and_file_urls() {
+        let path = std::env::temp_dir().join(format!("sonik-{}.m3u", std::process::id()));
+        let mut contents = b"#EXTM3U\n/music/Caf\xe9.mp3\n".to_vec();
+        contents.extend_from_slice(b"file:///music/My%20Song%2G.mp3\n");
+        fs::write(&path, contents).unwrap();
+
+        let entries = read_playlist(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            entries.unwrap(),
+            vec![
+                PathBuf::from("/music/Café.mp3"),
+                PathBuf::from("/music/My Song%2G.mp3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn percent_escapes_can_spell_out_utf8() {
+        assert_eq!(percent_decode("/music/Caf%C3%A9%"), "/music/Café%");
+    }
+}
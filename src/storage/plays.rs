@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+
+use bincode::{deserialize_from, serialize_into};
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::storage::record::Track;
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct PlayRecord {
+    pub count: u32,
+    pub last_played: i64,
+}
+
+// Play counts are kept apart from the library so that
+// they survive a rebuild of the database
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct PlayLog {
+    pub records: HashMap<String, PlayRecord>,
+}
+
+impl PlayLog {
+    pub fn load(path: &str) -> PlayLog {
+        // A missing or unreadable log just means nothing has been played yet
+        match fs::File::open(path) {
+            Ok(f) => deserialize_from(&mut BufReader::new(f)).unwrap_or_default(),
+            Err(_) => PlayLog::default(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ()> {
+        let f = fs::File::create(path).map_err(|_| ())?;
+        serialize_into(&mut BufWriter::new(f), &self).map_err(|_| ())
+    }
+
+    pub fn record(&mut self, track: &Track) {
+        let record = self.records.entry(track.file_path.clone()).or_default();
+        record.count += 1;
+        record.last_played = Local::now().timestamp();
+    }
+
+    pub fn count(&self, track: &Track) -> u32 {
+        self.records.get(&track.file_path).map_or(0, |r| r.count)
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+
+use bincode::{deserialize_from, serialize_into};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::storage::record::Track;
+
+// Highest number of stars a track can be given
+pub const MAX_RATING: u8 = 5;
+
+// Ratings are kept apart from the library, like play counts,
+// so that they survive a rebuild of the database
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct Ratings {
+    pub ratings: HashMap<String, u8>,
+}
+
+impl Ratings {
+    pub fn load(path: &str) -> Ratings {
+        match fs::File::open(path) {
+            Ok(f) => deserialize_from(&mut BufReader::new(f)).unwrap_or_default(),
+            Err(_) => Ratings::default(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ()> {
+        let f = fs::File::create(path).map_err(|_| ())?;
+        serialize_into(&mut BufWriter::new(f), &self).map_err(|_| ())
+    }
+
+    // Zero means the track hasn't been rated
+    pub fn rating(&self, track: &Track) -> u8 {
+        self.ratings.get(&track.file_path).cloned().unwrap_or(0)
+    }
+
+    // Goes up a star at a time, wrapping back around to unrated
+    pub fn cycle(&mut self, track: &Track) -> u8 {
+        let rating = (self.rating(track) + 1) % (MAX_RATING + 1);
+        if rating == 0 {
+            self.ratings.remove(&track.file_path);
+        } else {
+            self.ratings.insert(track.file_path.clone(), rating);
+        }
+
+        rating
+    }
+}
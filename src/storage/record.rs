@@ -1,14 +1,16 @@
 use std::borrow::Borrow;
 use std::cmp::Ordering;
+use std::fs;
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
 use id3::Tag;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::storage::vec_compare;
 
-#[derive(Clone, Eq, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Track {
     pub file_path: String,
     pub title: String,
@@ -18,14 +20,29 @@ pub struct Track {
     pub year: i32,
     pub track_num: u32,
     pub duration: u32,
+    pub genre: String,
+    // Seconds since the epoch at which the file was last modified
+    pub added: i64,
+    pub track_gain: Option<ReplayGain>,
+    pub album_gain: Option<ReplayGain>,
 }
 
-#[derive(Clone, Eq, Serialize, Deserialize, Debug)]
+// How far to turn a track or album up or down to even out the loudness
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReplayGain {
+    // In decibels
+    pub gain: f32,
+    // The loudest sample, where 1 is full scale
+    pub peak: Option<f32>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Album {
     pub title: String,
     pub artist: String,
     pub year: i32,
     pub tracks: Vec<Track>,
+    pub gain: Option<ReplayGain>,
 }
 
 #[derive(Clone, Eq, Serialize, Deserialize, Debug)]
@@ -117,6 +134,22 @@ impl Track {
             duration = x;
         }
 
+        let mut genre: String = "".to_string();
+        if let Some(s) = safe_tag.genre() {
+            genre = s.to_string();
+        }
+
+        // The modification time is the closest thing to "date added" we have
+        let mut added: i64 = 0;
+        if let Ok(t) = fs::metadata(&path).and_then(|m| m.modified()) {
+            if let Ok(d) = t.duration_since(UNIX_EPOCH) {
+                added = d.as_secs() as i64;
+            }
+        }
+
+        let track_gain = replay_gain(&safe_tag, "TRACK");
+        let album_gain = replay_gain(&safe_tag, "ALBUM");
+
         Ok(Track {
             file_path: path.as_path().to_string_lossy().to_string(),
             title,
@@ -126,6 +159,10 @@ impl Track {
             year,
             track_num,
             duration,
+            genre,
+            added,
+            track_gain,
+            album_gain,
         })
     }
 
@@ -140,10 +177,30 @@ impl Track {
             year: 0,
             track_num: 0,
             duration: 0,
+            genre: "".to_string(),
+            added: 0,
+            track_gain: None,
+            album_gain: None,
         }
     }
 }
 
+// Reads the REPLAYGAIN_TRACK_* or REPLAYGAIN_ALBUM_* user text frames,
+// which hold values like "-6.52 dB" and "0.988"
+fn replay_gain(tag: &Tag, scope: &str) -> Option<ReplayGain> {
+    let value = |name: &str| {
+        let description = format!("REPLAYGAIN_{}_{}", scope, name);
+        tag.extended_texts()
+            .find(|t| t.description.eq_ignore_ascii_case(&description))
+            .and_then(|t| t.value.split_whitespace().next()?.parse::<f32>().ok())
+    };
+
+    Some(ReplayGain {
+        gain: value("GAIN")?,
+        peak: value("PEAK"),
+    })
+}
+
 impl PartialOrd for Track {
     fn partial_cmp(&self, other: &Track) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -162,6 +219,9 @@ impl PartialEq for Track {
     }
 }
 
+// Tracks are compared by path alone, so the gains don't get in the way
+impl Eq for Track {}
+
 impl Record for Track {
     fn name(&self) -> &str {
         &self.title[..]
@@ -177,10 +237,15 @@ impl Album {
             artist: artist_name,
             year: release_year,
             tracks: tracklist,
+            gain: None,
         })
     }
 
     pub fn update_album(&mut self, t: Track) -> Result<(), ()> {
+        // Every track of an album should carry the same album gain
+        if self.gain.is_none() {
+            self.gain = t.album_gain;
+        }
         self.tracks.push(t);
 
         self.tracks.sort_by(|a, b| a.track_num.cmp(&b.track_num));
@@ -209,6 +274,8 @@ impl PartialEq for Album {
     }
 }
 
+impl Eq for Album {}
+
 impl Hash for Album {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.title.hash(state);
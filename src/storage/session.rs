@@ -0,0 +1,31 @@
+use std::fs;
+use std::io::{BufReader, BufWriter};
+
+use bincode::{deserialize_from, serialize_into};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::storage::record::Track;
+
+// What was playing and what was coming up when sonik last closed
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub queue: Vec<Track>,
+    pub now_playing: Option<Track>,
+    // Milliseconds into the track that was playing
+    pub position: u32,
+}
+
+impl Session {
+    pub fn load(path: &str) -> Session {
+        // Starting with an empty queue is fine if there is nothing to restore
+        match fs::File::open(path) {
+            Ok(f) => deserialize_from(&mut BufReader::new(f)).unwrap_or_default(),
+            Err(_) => Session::default(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ()> {
+        let f = fs::File::create(path).map_err(|_| ())?;
+        serialize_into(&mut BufWriter::new(f), &self).map_err(|_| ())
+    }
+}
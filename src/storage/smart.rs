@@ -0,0 +1,77 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+use toml;
+
+use crate::storage::plays::PlayLog;
+use crate::storage::record::{Artist, Record, Track};
+use crate::storage::terms::SearchQuery;
+
+// A named query that is run against the library whenever it is opened
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SmartPlaylist {
+    pub name: String,
+    pub query: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SmartPlaylistFile {
+    #[serde(default)]
+    playlist: Vec<SmartPlaylist>,
+}
+
+impl SmartPlaylist {
+    pub fn evaluate(&self, library: &[Artist], plays: &PlayLog) -> Vec<Track> {
+        let query = match SearchQuery::new(&self.query) {
+            Some(q) => q,
+            None => return Vec::new(),
+        };
+        let now = Local::now().timestamp();
+
+        library
+            .iter()
+            .flat_map(|artist| artist.albums.iter())
+            .flat_map(|album| album.tracks.iter())
+            .filter(|track| query.matches(track, plays, now))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Record for SmartPlaylist {
+    fn name(&self) -> &str {
+        &self.name[..]
+    }
+}
+
+pub fn load_smart_playlists(path: &str) -> Result<Vec<SmartPlaylist>, ()> {
+    if !Path::new(path).exists() {
+        write_default_smart_playlists(path)?;
+    }
+
+    let contents = fs::read_to_string(path).map_err(|_| ())?;
+    let file: SmartPlaylistFile = toml::from_str(&contents).map_err(|_| ())?;
+
+    Ok(file.playlist)
+}
+
+fn write_default_smart_playlists(path: &str) -> Result<(), ()> {
+    // A couple of examples so there is something to look at and edit
+    let file = SmartPlaylistFile {
+        playlist: vec![
+            SmartPlaylist {
+                name: "recently added".to_string(),
+                query: "added:<30".to_string(),
+            },
+            SmartPlaylist {
+                name: "never played".to_string(),
+                query: "plays:0".to_string(),
+            },
+        ],
+    };
+
+    let file_as_str = toml::to_string(&file).map_err(|_| ())?;
+    fs::write(path, file_as_str).map_err(|_| ())
+}
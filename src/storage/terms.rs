@@ -1,37 +1,155 @@
+use crate::storage::plays::PlayLog;
+use crate::storage::record::Track;
+use crate::storage::text::normalize;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// An inclusive range of values, either end of which may be left open
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl Range {
+    // Accepts "1970", "<1970", "<=1970", ">1970", ">=1970" and "1960-1969"
+    fn parse(input: &str) -> Option<Range> {
+        let input = input.trim();
+
+        let range = if let Some(rest) = input.strip_prefix("<=") {
+            Range {
+                min: None,
+                max: Some(rest.trim().parse().ok()?),
+            }
+        } else if let Some(rest) = input.strip_prefix(">=") {
+            Range {
+                min: Some(rest.trim().parse().ok()?),
+                max: None,
+            }
+        } else if let Some(rest) = input.strip_prefix('<') {
+            Range {
+                min: None,
+                max: Some(rest.trim().parse::<i64>().ok()? - 1),
+            }
+        } else if let Some(rest) = input.strip_prefix('>') {
+            Range {
+                min: Some(rest.trim().parse::<i64>().ok()? + 1),
+                max: None,
+            }
+        } else if let Some(idx) = input.find('-') {
+            Range {
+                min: Some(input[..idx].trim().parse().ok()?),
+                max: Some(input[idx + 1..].trim().parse().ok()?),
+            }
+        } else {
+            let value = input.parse().ok()?;
+            Range {
+                min: Some(value),
+                max: Some(value),
+            }
+        };
+
+        Some(range)
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.min.iter().all(|&min| value >= min) && self.max.iter().all(|&max| value <= max)
+    }
+}
+
 pub enum Term {
     Title(String),
     Album(String),
     Artist(String),
+    Genre(String),
+    Year(Range),
+    // Number of days since the track was added to the music folder
+    Added(Range),
+    Plays(Range),
 }
 
 impl Term {
     fn from_search_query(query: &str) -> Option<Term> {
-        let elements = query.split(':').collect::<Vec<&str>>();
+        let elements = query.splitn(2, ':').collect::<Vec<&str>>();
 
         if elements.len() <= 1 {
             None
         } else {
+            let value = elements[1].trim();
             match elements[0] {
-                "title" => Some(Term::Title(elements[1].into())),
-                "album" => Some(Term::Album(elements[1].into())),
-                "artist" => Some(Term::Artist(elements[1].into())),
+                "title" => Some(Term::Title(value.into())),
+                "album" => Some(Term::Album(value.into())),
+                "artist" => Some(Term::Artist(value.into())),
+                "genre" => Some(Term::Genre(value.into())),
+                "year" => Range::parse(value).map(Term::Year),
+                "added" => Range::parse(value).map(Term::Added),
+                "plays" => Range::parse(value).map(Term::Plays),
                 _ => None,
             }
         }
     }
+
+    fn is_key(word: &str) -> bool {
+        match word.split(':').next() {
+            Some("title") | Some("album") | Some("artist") | Some("genre") | Some("year")
+            | Some("added") | Some("plays") => word.contains(':'),
+            _ => false,
+        }
+    }
+
+    // Checks a single track against this term, used for evaluating
+    // saved queries against the whole library
+    pub fn matches(&self, track: &Track, plays: &PlayLog, now: i64) -> bool {
+        match self {
+            Term::Title(s) => contains(&track.title, s),
+            Term::Album(s) => contains(&track.album, s),
+            Term::Artist(s) => contains(&track.artist, s) || contains(&track.album_artist, s),
+            Term::Genre(s) => contains(&track.genre, s),
+            Term::Year(r) => r.contains(i64::from(track.year)),
+            Term::Added(r) => r.contains((now - track.added) / SECONDS_PER_DAY),
+            Term::Plays(r) => r.contains(i64::from(plays.count(track))),
+        }
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    normalize(haystack).contains(&normalize(needle))
 }
 
 // A search query will end up being just a collection of search terms
 pub struct SearchQuery {
-    pub terms: Term,
+    pub terms: Vec<Term>,
 }
 
 impl SearchQuery {
     pub fn new(input: &str) -> Option<SearchQuery> {
-        // Turns user input string into a collection of search terms
-        match Term::from_search_query(input) {
-            Some(terms) => Some(SearchQuery { terms }),
-            _ => None,
+        // Turns user input string into a collection of search terms; words
+        // without a key are added to the value of the term before them,
+        // so "title:so what year:<1960" is a title and a year term
+        let mut pieces: Vec<String> = Vec::new();
+        for word in input.split_whitespace() {
+            match pieces.last_mut() {
+                Some(piece) if !Term::is_key(word) => {
+                    piece.push(' ');
+                    piece.push_str(word);
+                }
+                _ => pieces.push(word.to_string()),
+            }
+        }
+
+        let terms = pieces
+            .iter()
+            .map(|p| Term::from_search_query(p))
+            .collect::<Option<Vec<Term>>>()?;
+
+        if terms.is_empty() {
+            None
+        } else {
+            Some(SearchQuery { terms })
         }
     }
+
+    pub fn matches(&self, track: &Track, plays: &PlayLog, now: i64) -> bool {
+        self.terms.iter().all(|t| t.matches(track, plays, now))
+    }
 }
@@ -0,0 +1,40 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Folds a string down to something that can be compared loosely, so that
+// "Sigur Rós" and "sigur ros" or "Guns N' Roses" and "guns n roses" match.
+// Only used for searching, the original text is what gets displayed.
+// The saved search index holds its output, so INDEX_VERSION in
+// database.rs goes up whenever it changes.
+pub fn normalize(input: &str) -> String {
+    let mut folded = String::with_capacity(input.len());
+
+    // Compatibility decomposition splits accented letters into the letter
+    // and its combining marks, which can then be dropped
+    for c in input.nfkd().filter(|c| !is_combining_mark(*c)) {
+        if c.is_alphanumeric() {
+            match fold_letter(c) {
+                Some(s) => folded.push_str(s),
+                None => folded.extend(c.to_lowercase()),
+            }
+        } else if c.is_whitespace() {
+            folded.push(' ');
+        }
+    }
+
+    folded.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Letters that have no decomposition but are commonly typed without the stroke
+fn fold_letter(c: char) -> Option<&'static str> {
+    match c {
+        'ß' => Some("ss"),
+        'æ' | 'Æ' => Some("ae"),
+        'œ' | 'Œ' => Some("oe"),
+        'ø' | 'Ø' => Some("o"),
+        'ł' | 'Ł' => Some("l"),
+        'đ' | 'Đ' => Some("d"),
+        'þ' | 'Þ' => Some("th"),
+        _ => None,
+    }
+}
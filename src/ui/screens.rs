@@ -1,14 +1,16 @@
 use std::io::{self, Write};
+use std::time::Duration;
 
 use chrono::Local;
 use tui::backend::Backend;
 use tui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
-use tui::widgets::{Block, Borders, List, Paragraph, Tabs, Text, Widget};
+use tui::widgets::{Block, Borders, Gauge, List, Paragraph, SelectableList, Tabs, Text, Widget};
 use tui::Frame;
 
-use crate::application::state::UI;
-use crate::ui::widgets::RecordList;
+use crate::application::state::{LibraryInput, UI};
+use crate::storage::playlist::Playlist;
+use crate::ui::widgets::{HitList, RecordList};
 
 // Yeah, I know this isn't elegant, but hey it works
 pub fn artist_color(app: &UI) -> Style {
@@ -63,42 +65,72 @@ where
         )
         .split(area);
 
-    let artists = app.queue.tracks.iter().map(|track| {
-        Text::styled(
-            track.artist.to_string(),
-            Style::default().fg(Color::LightGreen),
-        )
-    });
-
-    let titles = app.queue.tracks.iter().map(|track| {
-        Text::styled(
-            track.title.to_string(),
-            Style::default().fg(Color::LightBlue),
-        )
-    });
+    let titles = app
+        .queue
+        .tracks
+        .iter()
+        .map(|track| {
+            if app.missing.contains(&track.file_path) {
+                format!("{} (missing)", track.title)
+            } else {
+                track.title.to_string()
+            }
+        })
+        .collect::<Vec<String>>();
+
+    let artists = app
+        .queue
+        .tracks
+        .iter()
+        .map(|track| track.artist.to_string())
+        .collect::<Vec<String>>();
+
+    let albums = app
+        .queue
+        .tracks
+        .iter()
+        .map(|track| track.album.to_string())
+        .collect::<Vec<String>>();
+
+    let selected = if app.queue.is_empty() {
+        None
+    } else {
+        Some(app.queue_select)
+    };
 
-    let albums = app.queue.tracks.iter().map(|track| {
-        Text::styled(
-            track.album.to_string(),
-            Style::default().fg(Color::LightRed),
-        )
-    });
+    let highlight = Style::default()
+        .fg(Color::Rgb(255, 255, 0))
+        .modifier(Modifier::BOLD);
 
     Block::default()
         .borders(Borders::ALL)
         .title("up next")
         .render(f, area);
 
-    List::new(titles)
+    // All three columns share the selection so they scroll together
+    SelectableList::default()
         .block(Block::default())
+        .items(&titles)
+        .select(selected)
+        .style(Style::default().fg(Color::LightBlue))
+        .highlight_style(highlight)
+        .highlight_symbol(">>")
         .render(f, chunks[0]);
 
-    List::new(artists)
+    SelectableList::default()
         .block(Block::default())
+        .items(&artists)
+        .select(selected)
+        .style(Style::default().fg(Color::LightGreen))
+        .highlight_style(highlight)
         .render(f, chunks[1]);
 
-    List::new(albums)
+    SelectableList::default()
         .block(Block::default())
+        .items(&albums)
+        .select(selected)
+        .style(Style::default().fg(Color::LightRed))
+        .highlight_style(highlight)
         .render(f, chunks[2]);
 }
 
@@ -118,11 +150,16 @@ where
         .direction(Direction::Horizontal)
         .split(area);
 
+    let artist_title = filter_title(&app.lib_cols.artists.filter);
+    let album_title = filter_title(&app.lib_cols.albums.filter);
+    let track_title = filter_title(&app.lib_cols.tracks.filter);
+
     // This will be the artist block
     RecordList::default()
         .block(
             Block::default()
                 .borders(Borders::ALL)
+                .title(&artist_title)
                 .style(artist_color(&app)),
         )
         .items(&app.lib_cols.artists.items)
@@ -137,6 +174,7 @@ where
         .block(
             Block::default()
                 .borders(Borders::ALL)
+                .title(&album_title)
                 .style(album_color(&app)),
         )
         .items(&app.lib_cols.albums.items)
@@ -146,21 +184,41 @@ where
         .highlight_symbol(">>")
         .render(f, chunks[1]);
 
+    // Tracks that couldn't be played are greyed out
+    let missing = app
+        .lib_cols
+        .tracks
+        .items
+        .iter()
+        .map(|t| app.missing.contains(&t.file_path))
+        .collect::<Vec<bool>>();
+
     // This will be the songs of that album of that artist
     RecordList::default()
         .block(
             Block::default()
                 .borders(Borders::ALL)
+                .title(&track_title)
                 .style(track_color(&app)),
         )
         .items(&app.lib_cols.tracks.items)
         .select(Some(app.lib_cols.tracks.selected))
         .style(Style::default().fg(Color::White))
+        .marked(missing, Style::default().fg(Color::DarkGray))
         .highlight_style(track_color(&app).modifier(Modifier::BOLD))
         .highlight_symbol(">>")
         .render(f, chunks[2]);
 }
 
+// Filtered columns say so in their title
+fn filter_title(filter: &str) -> String {
+    if filter.is_empty() {
+        String::new()
+    } else {
+        format!("filter: {}", filter)
+    }
+}
+
 pub fn draw_search<B>(f: &mut Frame<B>, app: &UI, area: Rect)
 where
     B: Backend,
@@ -228,7 +286,8 @@ where
         .direction(Direction::Vertical)
         .split(area);
 
-    RecordList::default()
+    // Results are already sorted by how well they matched the query
+    HitList::default()
         .block(Block::default().borders(Borders::ALL).title("results"))
         .items(&app.search_results)
         .select(Some(app.search_select))
@@ -238,10 +297,118 @@ where
                 .fg(Color::Rgb(255, 255, 0))
                 .modifier(Modifier::BOLD),
         )
+        .match_style(
+            Style::default()
+                .fg(Color::LightCyan)
+                .modifier(Modifier::BOLD | Modifier::UNDERLINED),
+        )
+        .context_style(Style::default().fg(Color::DarkGray))
         .highlight_symbol(">>")
         .render(f, chunks[0]);
 }
 
+pub fn draw_playlists<B>(f: &mut Frame<B>, app: &UI, area: Rect)
+where
+    B: Backend,
+{
+    let chunks = Layout::default()
+        .constraints([Constraint::Ratio(1, 3), Constraint::Ratio(2, 3)].as_ref())
+        .direction(Direction::Horizontal)
+        .split(area);
+
+    let lists = Layout::default()
+        .constraints([Constraint::Percentage(60), Constraint::Percentage(40)].as_ref())
+        .direction(Direction::Vertical)
+        .split(chunks[0]);
+
+    // Saved playlists come before the smart ones, sharing one selection
+    let saved_count = app
+        .playlists
+        .items
+        .iter()
+        .take_while(|p| match p {
+            Playlist::Saved(_) => true,
+            Playlist::Smart(_) => false,
+        })
+        .count();
+    let selected = app.playlists.selected;
+
+    let highlight_style = Style::default()
+        .fg(Color::Rgb(255, 255, 0))
+        .modifier(Modifier::BOLD);
+
+    RecordList::default()
+        .block(Block::default().borders(Borders::ALL).title("playlists"))
+        .items(&app.playlists.items[..saved_count])
+        .select(if selected < saved_count {
+            Some(selected)
+        } else {
+            None
+        })
+        .style(Style::default().fg(Color::White))
+        .highlight_style(highlight_style)
+        .highlight_symbol(">>")
+        .render(f, lists[0]);
+
+    RecordList::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("smart playlists"),
+        )
+        .items(&app.playlists.items[saved_count..])
+        .select(selected.checked_sub(saved_count))
+        .style(Style::default().fg(Color::White))
+        .highlight_style(highlight_style)
+        .highlight_symbol(">>")
+        .render(f, lists[1]);
+
+    let tracks = app.playlist_tracks.iter().map(|track| {
+        Text::styled(
+            format!("{} - {} - {}", track.title, track.artist, track.album),
+            Style::default().fg(Color::LightBlue),
+        )
+    });
+
+    let title = if app.playlist_missing > 0 {
+        format!("tracks ({} not in library)", app.playlist_missing)
+    } else {
+        "tracks".to_string()
+    };
+
+    List::new(tracks)
+        .block(Block::default().borders(Borders::ALL).title(&title))
+        .render(f, chunks[1]);
+}
+
+pub fn draw_history<B>(f: &mut Frame<B>, app: &UI, area: Rect)
+where
+    B: Backend,
+{
+    let tracks = app
+        .history
+        .iter()
+        .map(|track| format!("{} - {} - {}", track.title, track.artist, track.album))
+        .collect::<Vec<String>>();
+
+    SelectableList::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("recently played"),
+        )
+        .items(&tracks)
+        .select(Some(app.history_select))
+        .style(Style::default().fg(Color::LightBlue))
+        .highlight_style(
+            Style::default()
+                .fg(Color::Rgb(255, 255, 0))
+                .modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">>")
+        .render(f, area);
+}
+
 pub fn draw_top_bar<B>(f: &mut Frame<B>, app: &UI, area: Rect)
 where
     B: Backend,
@@ -270,7 +437,7 @@ where
 
     draw_now_playing(f, chunks[1], app);
 
-    draw_status(f, chunks[2]);
+    draw_status(f, app, chunks[2]);
 }
 
 fn draw_now_playing<B>(f: &mut Frame<B>, area: Rect, app: &UI)
@@ -289,33 +456,91 @@ where
         ),
         Text::raw(" - "),
         Text::styled(&app.now_playing.album, Style::default().fg(Color::LightRed)),
+        Text::styled(
+            stars(app.ratings.rating(&app.now_playing)),
+            Style::default().fg(Color::Yellow),
+        ),
     ];
 
     let chunks = Layout::default()
-        .constraints([Constraint::Percentage(100)].as_ref())
-        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(65), Constraint::Percentage(35)].as_ref())
+        .direction(Direction::Horizontal)
         .margin(1)
         .split(area);
 
+    let mut title = format!(
+        "now playing | repeat: {} | shuffle: {}",
+        app.queue.repeat.label(),
+        app.queue.shuffle_mode.label()
+    );
+    match &app.radio {
+        Some(radio) if radio.query.is_empty() => title.push_str(" | radio"),
+        Some(radio) => title.push_str(&format!(" | radio: {}", radio.query)),
+        None => {}
+    }
+    if app.stop_after_current {
+        title.push_str(" | stopping after this");
+    }
+
     Block::default()
         .borders(Borders::ALL)
-        .title("now playing")
+        .title(&title)
         .render(f, area);
 
     Paragraph::new(track_info.iter())
         .alignment(Alignment::Center)
         .render(f, chunks[0]);
+
+    if app.now_playing.file_path.is_empty() {
+        return;
+    }
+
+    let elapsed = app.clock.elapsed();
+    let total = Duration::from_millis(u64::from(app.now_playing.duration));
+    let ratio = if total.as_millis() > 0 {
+        (elapsed.as_millis() as f64 / total.as_millis() as f64).min(1.)
+    } else {
+        0.
+    };
+    let label = format!("{} / {}", format_time(elapsed), format_time(total));
+
+    Gauge::default()
+        .style(Style::default().fg(Color::LightBlue).bg(Color::Black))
+        .ratio(ratio)
+        .label(&label)
+        .render(f, chunks[1]);
 }
 
-fn draw_status<B>(f: &mut Frame<B>, area: Rect)
+// Formats a duration like 3:07, or 1:02:45 when it runs over an hour
+fn format_time(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+fn stars(rating: u8) -> String {
+    if rating == 0 {
+        String::new()
+    } else {
+        format!(" {}", "*".repeat(usize::from(rating)))
+    }
+}
+
+fn draw_status<B>(f: &mut Frame<B>, app: &UI, area: Rect)
 where
     B: Backend,
 {
-    let text = [
-        Text::raw(Local::now().date().format("%A, %B %d, %Y").to_string()),
-        Text::raw(" | "),
-        Text::raw(Local::now().time().format("%H:%M:%S").to_string()),
-    ];
+    let text = match app.current_status() {
+        Some(message) => vec![Text::styled(message, Style::default().fg(Color::Yellow))],
+        None => vec![
+            Text::raw(Local::now().date().format("%A, %B %d, %Y").to_string()),
+            Text::raw(" | "),
+            Text::raw(Local::now().time().format("%H:%M:%S").to_string()),
+        ],
+    };
 
     let chunks = Layout::default()
         .constraints([Constraint::Percentage(100)].as_ref())
@@ -323,9 +548,15 @@ where
         .margin(1)
         .split(area);
 
+    let title = if app.muted {
+        "status | muted".to_string()
+    } else {
+        format!("status | vol: {:.0}%", app.volume * 100.)
+    };
+
     Block::default()
         .borders(Borders::ALL)
-        .title("status")
+        .title(&title)
         .render(f, area);
 
     Paragraph::new(text.iter())
@@ -368,10 +599,29 @@ where
         )),
     ];
 
+    let library_prompt = match &app.lib_cols.input {
+        Some(LibraryInput::Jump(s)) => [Text::raw(format!("jump to: {}", s))],
+        Some(LibraryInput::Filter(s)) if app.lib_cols.no_matches => {
+            [Text::raw(format!("filter: {} (no matches)", s))]
+        }
+        Some(LibraryInput::Filter(s)) => [Text::raw(format!("filter: {}", s))],
+        None => [Text::raw("")],
+    };
+
     let search_blurb = [Text::raw("Enter search query")];
 
+    let history_info = [Text::raw(format!("{} recently played", app.history.len()))];
+
     let search_info = [Text::raw(format!("{} results", app.search_results.len()))];
 
+    let playlist_info = [
+        Text::raw(format!("{} playlists", app.saved_playlists().len())),
+        Text::raw(" | "),
+        Text::raw(format!("{} smart playlists", app.smart_playlists.len())),
+        Text::raw(" | "),
+        Text::raw(format!("{} tracks", app.playlist_tracks.len())),
+    ];
+
     let chunks = Layout::default()
         .constraints([Constraint::Percentage(100)].as_ref())
         .direction(Direction::Vertical)
@@ -383,6 +633,18 @@ where
         .title("stats")
         .render(f, area);
 
+    // Anything being typed in takes the place of the stats
+    if let Some(prompt) = &app.prompt {
+        let prompt_text = [Text::styled(
+            format!("{}: {}", prompt.kind.label(), prompt.text),
+            Style::default().fg(Color::Yellow),
+        )];
+        Paragraph::new(prompt_text.iter())
+            .alignment(Alignment::Center)
+            .render(f, chunks[0]);
+        return;
+    }
+
     match app.tabs.index {
         0 => {
             Paragraph::new(queue_info.iter())
@@ -390,9 +652,15 @@ where
                 .render(f, chunks[0]);
         }
         1 => {
-            Paragraph::new(library_info.iter())
-                .alignment(Alignment::Center)
-                .render(f, chunks[0]);
+            if app.lib_cols.input.is_some() {
+                Paragraph::new(library_prompt.iter())
+                    .alignment(Alignment::Center)
+                    .render(f, chunks[0]);
+            } else {
+                Paragraph::new(library_info.iter())
+                    .alignment(Alignment::Center)
+                    .render(f, chunks[0]);
+            }
         }
         2 => {
             if !app.search_results.is_empty() {
@@ -405,6 +673,16 @@ where
                     .render(f, chunks[0]);
             }
         }
+        3 => {
+            Paragraph::new(playlist_info.iter())
+                .alignment(Alignment::Center)
+                .render(f, chunks[0]);
+        }
+        4 => {
+            Paragraph::new(history_info.iter())
+                .alignment(Alignment::Center)
+                .render(f, chunks[0]);
+        }
         _ => {}
     }
 }
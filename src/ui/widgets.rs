@@ -7,13 +7,17 @@ use tui::layout::Rect;
 use tui::style::Style;
 use tui::widgets::{Block, List, Text, Widget};
 
-use crate::storage::record::Record;
+use crate::storage::index::Hit;
+use crate::storage::record::{Media, Record};
 
 pub struct RecordList<'b> {
     block: Option<Block<'b>>,
     items: Vec<&'b str>,
     selected: Option<usize>,
     style: Style,
+    // Items drawn differently, e.g. tracks that couldn't be played
+    marked: Vec<bool>,
+    marked_style: Style,
     highlight_style: Style,
     highlight_symbol: Option<&'b str>,
 }
@@ -25,6 +29,8 @@ impl<'b> Default for RecordList<'b> {
             items: Vec::new(),
             selected: None,
             style: Default::default(),
+            marked: Vec::new(),
+            marked_style: Default::default(),
             highlight_style: Default::default(),
             highlight_symbol: None,
         }
@@ -47,6 +53,12 @@ impl<'b> RecordList<'b> {
         self
     }
 
+    pub fn marked(mut self, marked: Vec<bool>, style: Style) -> RecordList<'b> {
+        self.marked = marked;
+        self.marked_style = style;
+        self
+    }
+
     pub fn highlight_symbol(mut self, highlight_symbol: &'b str) -> RecordList<'b> {
         self.highlight_symbol = Some(highlight_symbol);
         self
@@ -98,14 +110,20 @@ impl<'b> Widget for RecordList<'b> {
             .iter()
             .enumerate()
             .map(|(i, &item)| {
+                let style = if self.marked.get(i) == Some(&true) {
+                    self.marked_style
+                } else {
+                    self.style
+                };
+
                 if let Some(s) = selected {
                     if i == s {
                         Text::styled(format!("{} {}", highlight_symbol, item), highlight_style)
                     } else {
-                        Text::styled(format!("{} {}", blank_symbol, item), self.style)
+                        Text::styled(format!("{} {}", blank_symbol, item), style)
                     }
                 } else {
-                    Text::styled(item, self.style)
+                    Text::styled(item, style)
                 }
             })
             .skip(offset as usize);
@@ -115,3 +133,140 @@ impl<'b> Widget for RecordList<'b> {
             .draw(area, buf);
     }
 }
+
+// Name, matched character ranges and context of a search hit
+type HitRow<'b> = (&'b str, &'b [(usize, usize)], String);
+
+// A list of search hits, where the matched parts of each name are
+// highlighted and some context is given about where the record lives
+#[derive(Default)]
+pub struct HitList<'b> {
+    block: Option<Block<'b>>,
+    items: Vec<HitRow<'b>>,
+    selected: Option<usize>,
+    style: Style,
+    highlight_style: Style,
+    match_style: Style,
+    context_style: Style,
+    highlight_symbol: Option<&'b str>,
+}
+
+impl<'b> HitList<'b> {
+    pub fn block(mut self, block: Block<'b>) -> HitList<'b> {
+        self.block = Some(block);
+        self
+    }
+
+    pub fn items(mut self, items: &'b [Hit<Media>]) -> HitList<'b> {
+        self.items = items
+            .iter()
+            .map(|h| (h.item.name(), &h.ranges[..], context(&h.item)))
+            .collect();
+        self
+    }
+
+    pub fn style(mut self, style: Style) -> HitList<'b> {
+        self.style = style;
+        self
+    }
+
+    pub fn highlight_symbol(mut self, highlight_symbol: &'b str) -> HitList<'b> {
+        self.highlight_symbol = Some(highlight_symbol);
+        self
+    }
+
+    pub fn highlight_style(mut self, highlight_style: Style) -> HitList<'b> {
+        self.highlight_style = highlight_style;
+        self
+    }
+
+    pub fn match_style(mut self, match_style: Style) -> HitList<'b> {
+        self.match_style = match_style;
+        self
+    }
+
+    pub fn context_style(mut self, context_style: Style) -> HitList<'b> {
+        self.context_style = context_style;
+        self
+    }
+
+    pub fn select(mut self, index: Option<usize>) -> HitList<'b> {
+        self.selected = index;
+        self
+    }
+}
+
+// Where a search result can be found, shown after its name
+fn context(media: &Media) -> String {
+    match media {
+        Media::Artist(a) => format!("{} album(s)", a.albums.len()),
+        Media::Album(a) => format!("{} ({})", a.artist, a.year),
+        Media::Track(t) => format!("{} - {}", t.artist, t.album),
+    }
+}
+
+impl<'b> Widget for HitList<'b> {
+    fn draw(&mut self, area: Rect, buf: &mut Buffer) {
+        let list_area = match self.block {
+            Some(ref mut b) => {
+                b.draw(area, buf);
+                b.inner(area)
+            }
+            None => area,
+        };
+
+        if list_area.width < 1 || list_area.height < 1 {
+            return;
+        }
+
+        self.background(list_area, buf, self.style.bg);
+
+        let list_height = list_area.height as usize;
+        let highlight_symbol = self.highlight_symbol.unwrap_or("");
+        let blank_symbol = " ".repeat(highlight_symbol.width());
+
+        // Make sure the list show the selected item
+        let offset = match self.selected {
+            Some(s) if s >= list_height => s - list_height + 1,
+            _ => 0,
+        };
+
+        for (row, (i, (name, ranges, context))) in self
+            .items
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .take(list_height)
+            .enumerate()
+        {
+            let (symbol, style) = if Some(i) == self.selected {
+                (highlight_symbol, self.highlight_style)
+            } else {
+                (&blank_symbol[..], self.style)
+            };
+
+            // Split the name into runs of matched and unmatched characters
+            let mut segments: Vec<(String, Style)> = vec![(format!("{} ", symbol), style)];
+            for (idx, c) in name.chars().enumerate() {
+                let matched = ranges.iter().any(|&(start, end)| idx >= start && idx < end);
+                let seg_style = if matched { self.match_style } else { style };
+                match segments.last_mut() {
+                    Some((s, st)) if *st == seg_style => s.push(c),
+                    _ => segments.push((c.to_string(), seg_style)),
+                }
+            }
+            segments.push((format!("  {}", context), self.context_style));
+
+            let y = list_area.top() + row as u16;
+            let mut x = list_area.left();
+            for (text, seg_style) in segments {
+                let remaining = list_area.right().saturating_sub(x) as usize;
+                if remaining == 0 {
+                    break;
+                }
+                buf.set_stringn(x, y, &text, remaining, seg_style);
+                x = x.saturating_add(text.width() as u16);
+            }
+        }
+    }
+}